@@ -0,0 +1 @@
+DROP TABLE requeuest_dead_letters;
@@ -0,0 +1,17 @@
+-- Requests which ran out of attempts without receiving an accepted response.
+CREATE TABLE requeuest_dead_letters (
+    id UUID PRIMARY KEY,
+    -- Name of the job which ran the request
+    name TEXT NOT NULL,
+    channel_name TEXT NOT NULL,
+    -- The serialized request
+    payload_bytes BYTEA NOT NULL,
+    -- Status code of the last response, if one was received
+    last_status INT,
+    -- Error of the last attempt, if no response was received
+    last_error TEXT,
+    created_at TIMESTAMPTZ NOT NULL,
+    failed_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX ON requeuest_dead_letters(channel_name, failed_at);
@@ -0,0 +1,11 @@
+-- Responses to requests spawned as returning jobs.
+CREATE TABLE requeuest_responses (
+    id UUID PRIMARY KEY,
+    status INT NOT NULL,
+    -- The serialized response headers
+    headers BYTEA NOT NULL,
+    body BYTEA NOT NULL,
+    completed_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX ON requeuest_responses(completed_at);
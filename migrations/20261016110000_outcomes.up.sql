@@ -0,0 +1,19 @@
+-- The outcome of the latest delivery attempt of each job.
+CREATE TABLE requeuest_outcomes (
+    id UUID PRIMARY KEY,
+    channel_name TEXT NOT NULL,
+    -- Number of attempts started so far
+    attempts INT NOT NULL DEFAULT 0,
+    -- Whether an attempt has been started without finishing yet
+    in_flight BOOLEAN NOT NULL DEFAULT FALSE,
+    last_attempt_at TIMESTAMPTZ,
+    -- Status code of the last response, if one was received
+    last_status INT,
+    -- Error of the last attempt, if no response was received
+    last_error TEXT,
+    -- When an accepted response was received
+    completed_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX ON requeuest_outcomes(last_attempt_at);
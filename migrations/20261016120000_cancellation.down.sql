@@ -0,0 +1 @@
+ALTER TABLE requeuest_outcomes DROP COLUMN cancelled_at;
@@ -0,0 +1,2 @@
+-- When a job was cancelled before its request was delivered.
+ALTER TABLE requeuest_outcomes ADD COLUMN cancelled_at TIMESTAMPTZ;
@@ -0,0 +1,3 @@
+-- How long was waited before the latest attempt, for retry policies which
+-- base the next wait on the previous one.
+ALTER TABLE requeuest_outcomes ADD COLUMN retry_delay_ms BIGINT;
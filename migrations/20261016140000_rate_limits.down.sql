@@ -0,0 +1 @@
+DROP TABLE requeuest_rate_limits;
@@ -0,0 +1,6 @@
+-- The state of the rate limits job runners share, stored as the time the next
+-- request would be allowed at if no bursts were allowed.
+CREATE TABLE requeuest_rate_limits (
+    key TEXT PRIMARY KEY,
+    allowed_at TIMESTAMPTZ NOT NULL
+);
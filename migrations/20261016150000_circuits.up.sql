@@ -0,0 +1,12 @@
+-- The circuit breaker state of each host requests have failed for.
+CREATE TABLE requeuest_circuits (
+    host TEXT PRIMARY KEY,
+    -- One of 'closed', 'open' or 'half_open'
+    state TEXT NOT NULL,
+    -- Number of attempts in a row which failed
+    failures INT NOT NULL DEFAULT 0,
+    -- When the circuit last tripped
+    opened_at TIMESTAMPTZ,
+    -- When the next probe may be sent, if the circuit isn't closed
+    retry_at TIMESTAMPTZ
+);
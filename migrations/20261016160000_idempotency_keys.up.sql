@@ -0,0 +1,9 @@
+-- The idempotency keys of spawned requests, along with the job which claimed
+-- each of them and whether it stores its response. A key can be claimed again
+-- once its window has passed.
+CREATE TABLE requeuest_idempotency_keys (
+    key TEXT PRIMARY KEY,
+    id UUID NOT NULL,
+    returning BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
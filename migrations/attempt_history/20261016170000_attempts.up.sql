@@ -0,0 +1,20 @@
+-- Every attempt to deliver a request, recorded if the attempt history is
+-- enabled.
+CREATE TABLE requeuest_attempts (
+    id UUID NOT NULL,
+    -- Number of the attempt, counting from 1
+    attempt INT NOT NULL,
+    started_at TIMESTAMPTZ NOT NULL,
+    duration_ms BIGINT NOT NULL,
+    -- Status code of the response, if one was received
+    status INT,
+    -- One of 'timeout', 'connect', 'redirect', 'body', 'request' or 'other',
+    -- if no response was received
+    error_kind TEXT,
+    error TEXT,
+    -- Start of the response body, if bodies are recorded
+    body BYTEA
+);
+
+CREATE INDEX ON requeuest_attempts(id, attempt);
+CREATE INDEX ON requeuest_attempts(started_at);
@@ -0,0 +1,262 @@
+//! Auth providers supply short-lived bearer tokens for requests, which would
+//! expire long before a request's last retry if they were stored with it.
+//! Providers are registered on the client by name with
+//! [`ClientBuilder::auth_provider`](crate::client::ClientBuilder::auth_provider),
+//! and requests refer to one with [`Request::auth_provider`]. The job runner
+//! caches the token of each provider until shortly before it expires, sends it
+//! in the `Authorization` header, and when the server responds with
+//! `401 Unauthorized`, fetches a new token and retries once right away, within
+//! the same attempt.
+
+use std::{
+	collections::HashMap,
+	fmt,
+	future::Future,
+	pin::Pin,
+	sync::Arc,
+	time::{Duration, Instant},
+};
+
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use url::Url;
+
+use crate::{error::AuthError, request::Request};
+
+/// How long before its expiry a token is refreshed, at most half of its
+/// lifetime.
+const REFRESH_MARGIN: Duration = Duration::from_secs(30);
+
+/// How long a token is cached for at most, even if it's valid for longer.
+const MAX_TOKEN_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long fetching a token may take. Jobs using the same provider wait for
+/// the token being fetched, so a hanging provider mustn't block them forever.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A boxed error of a custom auth provider.
+pub type ProviderError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// The future an auth provider returns, which resolves to a new token.
+pub type Fetch<'a> = Pin<Box<dyn Future<Output = Result<Token, AuthError>> + Send + 'a>>;
+
+/// A bearer token fetched by an auth provider.
+#[derive(Clone)]
+pub struct Token {
+	/// The token sent in the `Authorization` header.
+	pub access_token: String,
+	/// How long the token is valid for after it was fetched, if it expires.
+	/// Tokens are cached for a day at most, including ones which don't expire.
+	pub expires_in: Option<Duration>,
+}
+
+impl fmt::Debug for Token {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Token").field("expires_in", &self.expires_in).finish_non_exhaustive()
+	}
+}
+
+/// Fetches bearer tokens for requests. Implement it for ways of getting tokens
+/// other than the client credentials grant.
+pub trait AuthProvider: Send + Sync + 'static {
+	/// Fetches a new token, using the given HTTP client for any requests.
+	fn fetch<'a>(&'a self, client: &'a reqwest::Client) -> Fetch<'a>;
+}
+
+/// Fetches tokens with the OAuth2 client credentials grant, authenticating
+/// with HTTP basic authentication.
+///
+/// # Example
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use requeuest::auth::ClientCredentials;
+///
+/// let url = "https://auth.example.com/oauth/token".parse()?;
+/// let provider = ClientCredentials::new(url, "requeuest", "secret");
+/// let provider = provider.scope("webhooks:write");
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+#[must_use]
+pub struct ClientCredentials {
+	/// The token endpoint of the authorization server.
+	pub token_url: Url,
+	/// The ID of the client.
+	pub client_id: String,
+	/// The secret of the client.
+	pub client_secret: String,
+	/// The scopes the token is requested for.
+	pub scopes: Vec<String>,
+	/// Additional parameters sent to the token endpoint, e.g. an `audience`.
+	pub params: Vec<(String, String)>,
+}
+
+impl ClientCredentials {
+	/// Constructs a provider which fetches tokens from the given token endpoint
+	/// with the given client ID and secret.
+	pub fn new(
+		token_url: Url,
+		client_id: impl Into<String>,
+		client_secret: impl Into<String>,
+	) -> Self {
+		Self {
+			token_url,
+			client_id: client_id.into(),
+			client_secret: client_secret.into(),
+			scopes: Vec::new(),
+			params: Vec::new(),
+		}
+	}
+
+	/// Adds a scope the token is requested for.
+	pub fn scope(mut self, scope: impl Into<String>) -> Self {
+		self.scopes.push(scope.into());
+		self
+	}
+
+	/// Adds a parameter which is sent to the token endpoint.
+	pub fn param(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+		self.params.push((name.into(), value.into()));
+		self
+	}
+}
+
+impl fmt::Debug for ClientCredentials {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("ClientCredentials")
+			.field("token_url", &self.token_url)
+			.field("client_id", &self.client_id)
+			.field("scopes", &self.scopes)
+			.finish_non_exhaustive()
+	}
+}
+
+impl AuthProvider for ClientCredentials {
+	fn fetch<'a>(&'a self, client: &'a reqwest::Client) -> Fetch<'a> {
+		/// The successful response of a token endpoint.
+		#[derive(Deserialize)]
+		struct TokenResponse {
+			/// The issued token.
+			access_token: String,
+			/// How many seconds the token is valid for.
+			#[serde(default)]
+			expires_in: Option<u64>,
+		}
+
+		Box::pin(async move {
+			let mut form = vec![("grant_type", "client_credentials")];
+			let scope = self.scopes.join(" ");
+			if !scope.is_empty() {
+				form.push(("scope", &scope));
+			}
+			form.extend(self.params.iter().map(|(name, value)| (name.as_str(), value.as_str())));
+			let response = client
+				.post(self.token_url.clone())
+				.basic_auth(&self.client_id, Some(&self.client_secret))
+				.form(&form)
+				.send()
+				.await?;
+			if !response.status().is_success() {
+				return Err(AuthError::Rejected(response.status()));
+			}
+			let token: TokenResponse = serde_json::from_slice(&response.bytes().await?)?;
+			Ok(Token {
+				access_token: token.access_token,
+				expires_in: token.expires_in.map(Duration::from_secs),
+			})
+		})
+	}
+}
+
+/// A registered auth provider, along with its cached token.
+pub(crate) struct Provider {
+	/// The provider fetching tokens.
+	provider: Box<dyn AuthProvider>,
+	/// The cached token and when it has to be refreshed, if one was fetched.
+	/// Locked while fetching, so concurrent jobs wait for the same token.
+	cache: Mutex<Option<(HeaderValue, Instant)>>,
+}
+
+impl Provider {
+	/// Registers the given provider, without a cached token.
+	pub fn new(provider: impl AuthProvider) -> Self {
+		Self { provider: Box::new(provider), cache: Mutex::new(None) }
+	}
+}
+
+impl fmt::Debug for Provider {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Provider").finish_non_exhaustive()
+	}
+}
+
+/// Authorizes the requests of a job runner with the auth providers registered
+/// on its client.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Authenticator(Arc<HashMap<String, Provider>>);
+
+impl Authenticator {
+	/// Constructs an authenticator with the given providers.
+	pub fn new(providers: HashMap<String, Provider>) -> Self {
+		Self(Arc::new(providers))
+	}
+
+	/// Returns the provider the given request refers to, if it refers to one.
+	fn provider(&self, request: &Request) -> Result<Option<&Provider>, AuthError> {
+		let Some(name) = &request.auth_provider else {
+			return Ok(None);
+		};
+		self.0.get(name).map(Some).ok_or_else(|| AuthError::UnknownProvider(name.clone()))
+	}
+
+	/// Checks that the provider the given request refers to is registered, if
+	/// it refers to one.
+	pub fn check(&self, request: &Request) -> Result<(), AuthError> {
+		self.provider(request).map(|_| ())
+	}
+
+	/// Inserts the token of the auth provider the given request refers to, if
+	/// it refers to one, into the given headers, fetching a new one with the
+	/// given client if there is no valid one cached. A cached token equal to
+	/// `rejected` is replaced, as the server didn't accept it. Returns the
+	/// inserted value.
+	pub async fn authorize(
+		&self,
+		request: &Request,
+		client: &reqwest::Client,
+		headers: &mut HeaderMap,
+		rejected: Option<&HeaderValue>,
+	) -> Result<Option<HeaderValue>, AuthError> {
+		let Some(provider) = self.provider(request)? else {
+			return Ok(None);
+		};
+		let mut cache = provider.cache.lock().await;
+		let value = match &*cache {
+			Some((value, refresh_at))
+				if Some(value) != rejected && *refresh_at > Instant::now() =>
+			{
+				value.clone()
+			}
+			_ => {
+				let token = tokio::time::timeout(FETCH_TIMEOUT, provider.provider.fetch(client))
+					.await
+					.map_err(|_| AuthError::Timeout)??;
+				let mut value = HeaderValue::from_str(&format!("Bearer {}", token.access_token))
+					.map_err(AuthError::InvalidToken)?;
+				value.set_sensitive(true);
+				let lifetime =
+					token.expires_in.unwrap_or(MAX_TOKEN_LIFETIME).min(MAX_TOKEN_LIFETIME);
+				let now = Instant::now();
+				// A refresh time which can't be represented refreshes the token next time
+				let refresh_at =
+					now.checked_add(lifetime - REFRESH_MARGIN.min(lifetime / 2)).unwrap_or(now);
+				*cache = Some((value.clone(), refresh_at));
+				value
+			}
+		};
+		headers.insert(AUTHORIZATION, value.clone());
+		Ok(Some(value))
+	}
+}
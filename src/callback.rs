@@ -0,0 +1,331 @@
+//! Callbacks report the final state of a request to another URL, for
+//! producers which can't wait for the response with
+//! [`Client::spawn_returning`](crate::Client::spawn_returning). Once the
+//! request is delivered, given up on or expires, the job runner spawns a `POST`
+//! request with a JSON [`Report`] to the URL of its [`Callback`], in the same
+//! transaction the job finishes in. Cancelling the job through the
+//! [`Client`](crate::Client) reports it the same way. The report is delivered
+//! by the queue like any other request.
+
+use std::collections::BTreeMap;
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Executor, Postgres, Row};
+use sqlxmq::CurrentJob;
+use url::Url;
+use uuid::Uuid;
+
+use crate::{
+	client::default_job_proto, encryption::Cipher, job, request::Request, status::Outcome,
+};
+
+/// The size of the largest response body which is hashed for a report.
+const MAX_HASHED_BODY_SIZE: usize = 8 * 1024 * 1024;
+
+/// Where to report the final state of a request to.
+///
+/// # Example
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use requeuest::{callback::Callback, reqwest::header::ETAG, Request};
+///
+/// let url = "https://example.com/delivered".parse()?;
+/// let callback = Callback::new(url).report_header(ETAG);
+/// let request = Request::get("https://example.com/webhook")?;
+/// let request = request.callback(callback).build();
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[must_use]
+pub struct Callback {
+	/// The URL the report is posted to.
+	pub url: Url,
+	/// The channel the report is spawned on. Defaults to the channel of the
+	/// request. Reports aren't ordered after the other jobs on the channel.
+	pub channel: Option<String>,
+	/// The HTTP headers to send with the report, e.g. to authenticate it.
+	#[serde(with = "http_serde::header_map")]
+	pub headers: HeaderMap,
+	/// The names of the headers of the response which are included in the
+	/// report.
+	pub report_headers: Vec<String>,
+}
+
+impl Callback {
+	/// Constructs a callback which posts the report to the given URL.
+	pub fn new(url: Url) -> Self {
+		Self { url, channel: None, headers: HeaderMap::new(), report_headers: Vec::new() }
+	}
+
+	/// Sets the channel the report is spawned on.
+	pub fn channel(mut self, channel: impl Into<String>) -> Self {
+		self.channel = Some(channel.into());
+		self
+	}
+
+	/// Adds a header to send with the report.
+	pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+		self.headers.append(name, value);
+		self
+	}
+
+	/// Includes the header of the response with the given name in the report.
+	pub fn report_header(mut self, name: HeaderName) -> Self {
+		self.report_headers.push(name.as_str().to_owned());
+		self
+	}
+
+	/// Picks the parts of a response with the given headers and body which
+	/// are reported. The body is `None` if reading it failed.
+	pub(crate) fn received(&self, headers: &HeaderMap, body: Option<&[u8]>) -> Received {
+		let body_sha256 = body
+			.filter(|body| body.len() <= MAX_HASHED_BODY_SIZE)
+			.map(|body| hex::encode(Sha256::digest(body)));
+		Received { headers: self.reported_headers(headers), body_sha256 }
+	}
+
+	/// Reads the given response, and picks the parts of it which are
+	/// reported. The body is hashed as it's read, and only read as far as
+	/// it's hashed or kept. Returns the start of the body along with them, up
+	/// to the given size if there is one.
+	pub(crate) async fn read(
+		&self,
+		mut response: reqwest::Response,
+		keep: Option<usize>,
+	) -> (Received, Vec<u8>) {
+		let headers = self.reported_headers(response.headers());
+		let keep = keep.unwrap_or_default();
+		let mut start = Vec::new();
+		let mut digest = Some(Sha256::new());
+		let mut size = 0_usize;
+		while digest.is_some() || start.len() < keep {
+			let chunk = match response.chunk().await {
+				Ok(Some(chunk)) => chunk,
+				Ok(None) => break,
+				// A body which can't be read is left out of the report
+				Err(_) => {
+					digest = None;
+					break;
+				}
+			};
+			start.extend_from_slice(&chunk[..chunk.len().min(keep.saturating_sub(start.len()))]);
+			size = size.saturating_add(chunk.len());
+			if size > MAX_HASHED_BODY_SIZE {
+				digest = None;
+			}
+			if let Some(hasher) = &mut digest {
+				hasher.update(&chunk);
+			}
+		}
+		let body_sha256 = digest.map(|digest| hex::encode(digest.finalize()));
+		(Received { headers, body_sha256 }, start)
+	}
+
+	/// Picks the headers of a response which are reported.
+	fn reported_headers(&self, headers: &HeaderMap) -> BTreeMap<String, String> {
+		self.report_headers
+			.iter()
+			.filter_map(|name| {
+				let values: Vec<&str> =
+					headers.get_all(name).iter().filter_map(|value| value.to_str().ok()).collect();
+				(!values.is_empty()).then(|| (name.clone(), values.join(", ")))
+			})
+			.collect()
+	}
+}
+
+/// The final state of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinalState {
+	/// The request was delivered.
+	Delivered,
+	/// The request ran out of attempts and was moved to the dead letter table.
+	Dead,
+	/// The request expired before it was delivered.
+	Expired,
+	/// The job was cancelled before the request was delivered.
+	Cancelled,
+}
+
+/// The parts of the last response to a request which are reported.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Received {
+	/// The reported headers of the response.
+	headers: BTreeMap<String, String>,
+	/// The hex encoded SHA-256 digest of the body of the response, if it was
+	/// read.
+	body_sha256: Option<String>,
+}
+
+/// The report of the final state of a request, which is posted to its
+/// callback as JSON.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Report {
+	/// The UUID of the job which sent the request.
+	pub id: Uuid,
+	/// The channel the request was spawned on.
+	pub channel: String,
+	/// The final state of the request.
+	pub state: FinalState,
+	/// The status code of the last response, if one was received.
+	pub status: Option<u16>,
+	/// The error of the last attempt, if no response was received.
+	pub error: Option<String>,
+	/// The headers of the last response which the callback asked for.
+	pub headers: BTreeMap<String, String>,
+	/// The hex encoded SHA-256 digest of the body of the last response, if it
+	/// was read. Bodies larger than 8 MiB aren't hashed.
+	pub body_sha256: Option<String>,
+	/// The number of attempts which were made to deliver the request.
+	pub attempts: u32,
+}
+
+impl Report {
+	/// Constructs the report of a request whose last attempt had the given
+	/// outcome, with the given parts of the response if one was received.
+	pub(crate) fn new(
+		id: Uuid,
+		channel: &str,
+		state: FinalState,
+		outcome: Option<&Outcome>,
+		received: Option<Received>,
+		attempts: u32,
+	) -> Self {
+		let (status, error) = match outcome {
+			Some(Outcome::Response(status)) => (Some(status.as_u16()), None),
+			Some(Outcome::Error(error)) => (None, Some(error.clone())),
+			None => (None, None),
+		};
+		let received = received.unwrap_or_default();
+		Self {
+			id,
+			channel: channel.to_owned(),
+			state,
+			status,
+			error,
+			headers: received.headers,
+			body_sha256: received.body_sha256,
+			attempts,
+		}
+	}
+
+	/// Constructs the report of the given job, whose request expired, from
+	/// the outcome of its last attempt. Has to be called before the job is
+	/// completed.
+	pub(crate) async fn expired(job: &CurrentJob) -> Result<Self, sqlx::Error> {
+		let row = sqlx::query(
+			"SELECT
+				mq_msgs.channel_name,
+				COALESCE(outcomes.attempts, 0) AS attempts,
+				outcomes.last_status,
+				outcomes.last_error
+			FROM mq_msgs
+			LEFT JOIN requeuest_outcomes AS outcomes ON outcomes.id = mq_msgs.id
+			WHERE mq_msgs.id = $1",
+		)
+		.bind(job.id())
+		.fetch_one(job.pool())
+		.await?;
+		let channel: String = row.try_get("channel_name")?;
+		let attempts: i32 = row.try_get("attempts")?;
+		let outcome =
+			Outcome::from_columns(row.try_get("last_status")?, row.try_get("last_error")?);
+		Ok(Self::new(
+			job.id(),
+			&channel,
+			FinalState::Expired,
+			outcome.as_ref(),
+			None,
+			u32::try_from(attempts).unwrap_or_default(),
+		))
+	}
+
+	/// Serializes the request which posts the report to the given callback,
+	/// sealing it with the given cipher.
+	pub(crate) fn request(
+		&self,
+		callback: &Callback,
+		cipher: &Cipher,
+	) -> Result<Followup, Box<dyn std::error::Error + Send + Sync + 'static>> {
+		let mut request = Request::post(callback.url.clone(), serde_json::to_vec(self)?)?.build();
+		request.headers = callback.headers.clone();
+		request.headers.entry(CONTENT_TYPE).or_insert(HeaderValue::from_static("application/json"));
+		Ok(Followup {
+			channel: callback.channel.clone().unwrap_or_else(|| self.channel.clone()),
+			bytes: cipher.encode(&request)?,
+		})
+	}
+}
+
+/// A serialized request which posts a report to a callback.
+#[derive(Debug)]
+pub(crate) struct Followup {
+	/// The channel the request is spawned on.
+	channel: String,
+	/// The serialized request.
+	bytes: Vec<u8>,
+}
+
+impl Followup {
+	/// Spawns the request with the given executor. The request isn't ordered
+	/// after the other jobs on its channel: it's spawned in the transactions
+	/// jobs finish in, where inserting an ordered job can fail when it races
+	/// with another one, failing the job after its request was delivered.
+	pub async fn spawn<'e, E>(&self, executor: E) -> Result<(), sqlx::Error>
+	where
+		E: Executor<'e, Database = Postgres>,
+	{
+		let mut builder = job::http.builder();
+		builder
+			.set_proto(default_job_proto)
+			.set_ordered(false)
+			.set_channel_name(&self.channel)
+			.set_raw_bytes(&self.bytes)
+			.spawn(executor)
+			.await?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	#![allow(clippy::unwrap_used)]
+	use reqwest::header::{HeaderMap, ETAG, SERVER};
+	use uuid::Uuid;
+
+	use super::{Callback, FinalState, Report};
+	use crate::status::Outcome;
+
+	#[test]
+	fn report() {
+		let callback = Callback::new("https://example.com/".parse().unwrap()).report_header(ETAG);
+		let mut headers = HeaderMap::new();
+		headers.insert(ETAG, "\"v1\"".parse().unwrap());
+		headers.insert(SERVER, "hyper".parse().unwrap());
+		let received = callback.received(&headers, Some(b"OK"));
+
+		let outcome = Outcome::Response(reqwest::StatusCode::OK);
+		let id = Uuid::new_v4();
+		let report =
+			Report::new(id, "channel", FinalState::Delivered, Some(&outcome), Some(received), 2);
+		let json: serde_json::Value = serde_json::to_value(&report).unwrap();
+		assert_eq!(json["state"], "delivered");
+		assert_eq!(json["status"], 200);
+		assert_eq!(json["attempts"], 2);
+		assert_eq!(json["headers"], serde_json::json!({ "etag": "\"v1\"" }));
+		assert_eq!(
+			json["body_sha256"],
+			"565339bc4d33d72817b583024112eb7f5cdf3e5eef0252d6ec1b9c9a94e12bb3"
+		);
+
+		let outcome = Outcome::Error("connection refused".to_owned());
+		let report = Report::new(id, "channel", FinalState::Dead, Some(&outcome), None, 1);
+		assert_eq!(report.status, None);
+		assert_eq!(report.error.as_deref(), Some("connection refused"));
+		assert_eq!(report.body_sha256, None);
+	}
+}
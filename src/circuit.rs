@@ -0,0 +1,320 @@
+//! Circuit breakers stop job runners from sending requests to hosts which keep
+//! failing. Once a host fails a number of times in a row, its circuit trips
+//! and no requests are sent to it for a cool-down period, after which a single
+//! probe is let through to see whether it has recovered. The state of each
+//! circuit is kept in the database, so it's shared by every process using it.
+
+use std::{sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use reqwest::StatusCode;
+use sqlx::{postgres::PgRow, PgPool, Row};
+use tokio::sync::broadcast;
+
+use crate::request::Request;
+
+/// The settings of the circuit breakers of each host.
+///
+/// # Example
+/// ```
+/// use std::time::Duration;
+///
+/// use requeuest::circuit::CircuitBreaker;
+///
+/// let breaker = CircuitBreaker::new(5, Duration::from_secs(60));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[must_use]
+pub struct CircuitBreaker {
+	/// How many attempts in a row have to fail for a circuit to trip.
+	pub failure_threshold: u32,
+	/// How long to stop sending requests to a host after its circuit tripped.
+	pub cool_down: Duration,
+}
+
+impl CircuitBreaker {
+	/// Constructs circuit breaker settings which trip after the given number
+	/// of failed attempts in a row, for the given cool-down period.
+	pub fn new(failure_threshold: u32, cool_down: Duration) -> Self {
+		Self { failure_threshold, cool_down }
+	}
+}
+
+/// The state of the circuit of a host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+	/// Requests are sent to the host.
+	Closed,
+	/// No requests are sent to the host until the cool-down period has
+	/// passed.
+	Open,
+	/// A single probe request is being sent to the host, and the circuit
+	/// closes again if it succeeds.
+	HalfOpen,
+}
+
+impl CircuitState {
+	/// Returns the name the state is stored as.
+	fn as_str(self) -> &'static str {
+		match self {
+			CircuitState::Closed => "closed",
+			CircuitState::Open => "open",
+			CircuitState::HalfOpen => "half_open",
+		}
+	}
+
+	/// Parses the name the state is stored as.
+	fn parse(state: &str) -> Self {
+		match state {
+			"open" => CircuitState::Open,
+			"half_open" => CircuitState::HalfOpen,
+			_ => CircuitState::Closed,
+		}
+	}
+}
+
+/// The circuit of a host.
+#[derive(Debug, Clone)]
+pub struct Circuit {
+	/// The host the circuit belongs to.
+	pub host: String,
+	/// The state of the circuit.
+	pub state: CircuitState,
+	/// The number of attempts in a row which failed.
+	pub consecutive_failures: u32,
+	/// When the circuit last tripped.
+	pub opened_at: Option<DateTime<Utc>>,
+	/// When the next probe may be sent, if the circuit isn't closed.
+	pub retry_at: Option<DateTime<Utc>>,
+}
+
+impl Circuit {
+	/// Constructs a circuit from a row of the circuit table.
+	fn from_row(row: &PgRow) -> Result<Self, sqlx::Error> {
+		let failures: i32 = row.try_get("failures")?;
+		Ok(Self {
+			host: row.try_get("host")?,
+			state: CircuitState::parse(row.try_get("state")?),
+			consecutive_failures: u32::try_from(failures).unwrap_or_default(),
+			opened_at: row.try_get("opened_at")?,
+			retry_at: row.try_get("retry_at")?,
+		})
+	}
+}
+
+/// A change in the state of a circuit caused by a job runner of this client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CircuitEvent {
+	/// The circuit of the host tripped after the given number of failed
+	/// attempts in a row.
+	Tripped {
+		/// The host whose circuit tripped.
+		host: String,
+		/// The number of attempts in a row which failed.
+		failures: u32,
+	},
+	/// The circuit of the host closed again after a successful probe.
+	Reset {
+		/// The host whose circuit closed.
+		host: String,
+	},
+}
+
+/// Whether a request may be sent to its host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Permit {
+	/// The request may be sent.
+	Allowed,
+	/// The circuit of the host is open, and the request should wait for the
+	/// given amount of time.
+	Wait(Duration),
+}
+
+/// The circuit breakers a job runner enforces.
+#[derive(Debug, Clone)]
+pub(crate) struct Breaker(Arc<Inner>);
+
+/// The shared state of a [`Breaker`].
+#[derive(Debug)]
+struct Inner {
+	/// The circuit breaker settings, if circuit breaking is enabled.
+	settings: Option<CircuitBreaker>,
+	/// The sender circuit events are broadcast through.
+	events: broadcast::Sender<CircuitEvent>,
+}
+
+impl Breaker {
+	/// Constructs a breaker with the given settings, which broadcasts circuit
+	/// events through the given sender.
+	pub fn new(settings: Option<CircuitBreaker>, events: broadcast::Sender<CircuitEvent>) -> Self {
+		Self(Arc::new(Inner { settings, events }))
+	}
+
+	/// Checks whether the given request may be sent to its host, letting a
+	/// probe through once the cool-down period of an open circuit has passed.
+	pub async fn permit(&self, pool: &PgPool, request: &Request) -> Result<Permit, sqlx::Error> {
+		let (Some(settings), Some(host)) = (self.0.settings, request.url.host_str()) else {
+			return Ok(Permit::Allowed);
+		};
+
+		// The probe pushes the next retry back by a cool-down period, so another
+		// probe is let through if it never finishes.
+		let probe = sqlx::query(
+			"UPDATE requeuest_circuits
+			SET state = $2, retry_at = NOW() + $3 * INTERVAL '1 millisecond'
+			WHERE host = $1 AND state <> $4 AND retry_at <= NOW()",
+		)
+		.bind(host)
+		.bind(CircuitState::HalfOpen.as_str())
+		.bind(millis(settings.cool_down))
+		.bind(CircuitState::Closed.as_str())
+		.execute(pool)
+		.await?
+		.rows_affected()
+			> 0;
+		if probe {
+			return Ok(Permit::Allowed);
+		}
+
+		let wait: Option<i64> = sqlx::query_scalar(
+			"SELECT (EXTRACT(EPOCH FROM retry_at - NOW()) * 1000)::BIGINT
+			FROM requeuest_circuits
+			WHERE host = $1 AND state <> $2",
+		)
+		.bind(host)
+		.bind(CircuitState::Closed.as_str())
+		.fetch_optional(pool)
+		.await?
+		.flatten();
+		Ok(match wait {
+			None => Permit::Allowed,
+			// The circuit might have been probed in between the queries
+			Some(wait) => {
+				Permit::Wait(Duration::from_millis(u64::try_from(wait).unwrap_or_default().max(1)))
+			}
+		})
+	}
+
+	/// Records the result of an attempt to send the given request, where
+	/// `status` is the status of the response, if one was received. Transport
+	/// errors and server errors count as failures.
+	pub async fn record(
+		&self,
+		pool: &PgPool,
+		request: &Request,
+		status: Option<StatusCode>,
+	) -> Result<(), sqlx::Error> {
+		let (Some(settings), Some(host)) = (self.0.settings, request.url.host_str()) else {
+			return Ok(());
+		};
+		if status.is_some_and(|status| !status.is_server_error()) {
+			self.succeeded(pool, host).await
+		} else {
+			self.failed(pool, host, settings).await
+		}
+	}
+
+	/// Closes the circuit of the given host after a successful attempt.
+	async fn succeeded(&self, pool: &PgPool, host: &str) -> Result<(), sqlx::Error> {
+		let previous: Option<String> = sqlx::query_scalar(
+			"UPDATE requeuest_circuits
+			SET state = $2, failures = 0, retry_at = NULL
+			FROM (SELECT host, state FROM requeuest_circuits WHERE host = $1 FOR UPDATE) AS previous
+			WHERE requeuest_circuits.host = previous.host
+			AND (previous.state <> $2 OR requeuest_circuits.failures > 0)
+			RETURNING previous.state",
+		)
+		.bind(host)
+		.bind(CircuitState::Closed.as_str())
+		.fetch_optional(pool)
+		.await?;
+
+		if previous.is_some_and(|state| CircuitState::parse(&state) != CircuitState::Closed) {
+			self.emit(CircuitEvent::Reset { host: host.to_owned() });
+		}
+		Ok(())
+	}
+
+	/// Counts a failed attempt against the circuit of the given host, tripping
+	/// it if there were too many failures in a row or the attempt was a probe.
+	async fn failed(
+		&self,
+		pool: &PgPool,
+		host: &str,
+		settings: CircuitBreaker,
+	) -> Result<(), sqlx::Error> {
+		let mut tx = pool.begin().await?;
+		sqlx::query(
+			"INSERT INTO requeuest_circuits (host, state) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+		)
+		.bind(host)
+		.bind(CircuitState::Closed.as_str())
+		.execute(&mut *tx)
+		.await?;
+		let row = sqlx::query("SELECT * FROM requeuest_circuits WHERE host = $1 FOR UPDATE")
+			.bind(host)
+			.fetch_one(&mut *tx)
+			.await?;
+		let circuit = Circuit::from_row(&row)?;
+
+		let failures = circuit.consecutive_failures.saturating_add(1);
+		let trip = match circuit.state {
+			CircuitState::Closed => failures >= settings.failure_threshold,
+			CircuitState::HalfOpen => true,
+			CircuitState::Open => false,
+		};
+		sqlx::query(
+			"UPDATE requeuest_circuits SET
+				failures = $2,
+				state = CASE WHEN $3 THEN $4 ELSE state END,
+				opened_at = CASE WHEN $3 THEN NOW() ELSE opened_at END,
+				retry_at = CASE WHEN $3 THEN NOW() + $5 * INTERVAL '1 millisecond' ELSE retry_at END
+			WHERE host = $1",
+		)
+		.bind(host)
+		.bind(i32::try_from(failures).unwrap_or(i32::MAX))
+		.bind(trip)
+		.bind(CircuitState::Open.as_str())
+		.bind(millis(settings.cool_down))
+		.execute(&mut *tx)
+		.await?;
+		tx.commit().await?;
+
+		if trip {
+			self.emit(CircuitEvent::Tripped { host: host.to_owned(), failures });
+		}
+		Ok(())
+	}
+
+	/// Broadcasts a circuit event to everyone listening for them.
+	fn emit(&self, event: CircuitEvent) {
+		// Nobody listening for events isn't an error
+		self.0.events.send(event).ok();
+	}
+}
+
+/// Converts a duration to milliseconds, saturating on overflow.
+fn millis(duration: Duration) -> i64 {
+	i64::try_from(duration.as_millis()).unwrap_or(i64::MAX)
+}
+
+/// Fetches the circuit of the given host, if any request to it has failed.
+pub(crate) async fn fetch(pool: &PgPool, host: &str) -> Result<Option<Circuit>, sqlx::Error> {
+	sqlx::query("SELECT * FROM requeuest_circuits WHERE host = $1")
+		.bind(host)
+		.fetch_optional(pool)
+		.await?
+		.as_ref()
+		.map(Circuit::from_row)
+		.transpose()
+}
+
+/// Fetches the circuits of all hosts which any request has failed for.
+pub(crate) async fn fetch_all(pool: &PgPool) -> Result<Vec<Circuit>, sqlx::Error> {
+	sqlx::query("SELECT * FROM requeuest_circuits ORDER BY host ASC")
+		.fetch_all(pool)
+		.await?
+		.iter()
+		.map(Circuit::from_row)
+		.collect()
+}
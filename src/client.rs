@@ -1,18 +1,62 @@
 //! The `Client` holds the job listener and database connection, which is used
 //! to spawn jobs.
 
-use std::borrow::Cow;
+#[cfg(feature = "tracing")]
+use std::collections::HashSet;
+use std::{borrow::Cow, collections::HashMap, time::Duration};
 
-use sqlx::PgPool;
+use chrono::{DateTime, Utc};
+#[cfg(feature = "tracing")]
+use reqwest::header::HeaderName;
+use reqwest::header::HeaderValue;
+use sqlx::{postgres::PgListener, Connection, PgPool, Postgres, Transaction};
 use sqlxmq::{JobBuilder, JobRegistry, JobRunnerHandle};
-use tokio::sync::oneshot;
+use tokio::{
+	sync::{broadcast, oneshot},
+	task::JoinHandle,
+};
 use uuid::Uuid;
 
-use crate::{error::SpawnError, job, job::ResponseSender, request::Request};
+#[cfg(feature = "metrics")]
+use crate::metric;
+#[cfg(feature = "tracing")]
+use crate::trace;
+use crate::{
+	auth::{AuthProvider, Authenticator, Provider},
+	circuit::{self, Breaker, Circuit, CircuitBreaker, CircuitEvent},
+	dead_letter::{self, DeadLetter},
+	encryption::{Cipher, Encryption},
+	error::{ClientError, SpawnError},
+	history::{self, AttemptHistory, AttemptRecord, Recorder},
+	hooks::{Hooks, Notifier},
+	idempotency, job,
+	job::{Credentials, ResponseSender},
+	rate_limit::{Limits, RateLimit, RateLimiter},
+	request::Request,
+	response::{self, Response},
+	retry::{self, RetryAfterLimit, RetryPolicy},
+	secret::{SecretResolver, Secrets},
+	signing::{Signer, SigningKey},
+	status::{self, Cancellation, JobStatus, QueueStats},
+	trace_context::TraceContext,
+	transport::{HttpClient, Transport},
+};
+
+/// The number of circuit events kept for subscribers which fall behind.
+const CIRCUIT_EVENT_CAPACITY: usize = 64;
+
+/// The number of times jobs are retried by default.
+const DEFAULT_RETRIES: usize = 100_000;
+
+/// The initial backoff between retries sqlxmq uses by default.
+const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How long idempotency keys deduplicate requests by default.
+const DEFAULT_IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
 
 /// Prototype function that applies default settings for sqlx jobs
-fn default_job_proto<'a>(builder: &'a mut JobBuilder<'a>) -> &'a mut JobBuilder<'a> {
-	builder.set_retries(100_000).set_ordered(true)
+pub(crate) fn default_job_proto<'a>(builder: &'a mut JobBuilder<'a>) -> &'a mut JobBuilder<'a> {
+	builder.set_retries(DEFAULT_RETRIES).set_ordered(true)
 }
 
 /// The list of channels the client should listen on
@@ -24,6 +68,17 @@ pub enum Channels<'a> {
 	List(&'a [&'a str]),
 }
 
+impl<'a> Channels<'a> {
+	/// Returns the channel names to filter by in SQL queries, or `None` if all
+	/// channels should match.
+	fn filter(&self) -> Option<&'a [&'a str]> {
+		match self {
+			Channels::All => None,
+			Channels::List(list) => Some(list),
+		}
+	}
+}
+
 /// The client is used for listening for and spawning new jobs.
 pub struct Client {
 	/// The database connection pool.
@@ -33,6 +88,346 @@ pub struct Client {
 	listener: Option<JobRunnerHandle>,
 	/// A map of oneshot channels which successful responses are sent through.
 	response_sender: ResponseSender,
+	/// The handle to the tokio task which listens for responses to returning
+	/// jobs run by other processes.
+	response_listener: JoinHandle<()>,
+	/// The handle to the tokio task which removes the attempts past the
+	/// retention of the attempt history, if it's enabled.
+	attempt_expiry: Option<JoinHandle<()>>,
+	/// The retry policies of requests spawned on each channel, unless they
+	/// have their own.
+	retry_policies: HashMap<String, RetryPolicy>,
+	/// The sender the job runner broadcasts circuit events through.
+	circuit_events: broadcast::Sender<CircuitEvent>,
+	/// Serializes spawned requests, and encrypts them if enabled.
+	cipher: Cipher,
+	/// The credentials registered on the client, which spawned requests may
+	/// only refer to.
+	credentials: Credentials,
+	/// How long an idempotency key deduplicates requests after being claimed.
+	idempotency_window: Duration,
+	/// Whether spawned requests send the trace context they were spawned in
+	/// to the server.
+	propagate_trace_context: bool,
+	/// The headers whose values are redacted from traces.
+	#[cfg(feature = "tracing")]
+	redacted_headers: HashSet<HeaderName>,
+}
+
+impl Drop for Client {
+	fn drop(&mut self) {
+		self.response_listener.abort();
+		if let Some(attempt_expiry) = &self.attempt_expiry {
+			attempt_expiry.abort();
+		}
+	}
+}
+
+/// Builder for a [`Client`] with custom settings, constructed with
+/// [`Client::builder`].
+#[derive(Debug)]
+#[must_use]
+pub struct ClientBuilder<'a> {
+	/// The database connection pool.
+	pool: PgPool,
+	/// The channels to listen for jobs on.
+	channels: Channels<'a>,
+	/// The default timeout of a single attempt to send a request.
+	timeout: Option<Duration>,
+	/// The default connect timeout of a single attempt to send a request.
+	connect_timeout: Option<Duration>,
+	/// How the HTTP clients requests are sent with are built by default.
+	http_client: HttpClient,
+	/// How the HTTP clients requests spawned on each channel are sent with are
+	/// built.
+	channel_http_clients: HashMap<String, HttpClient>,
+	/// The retry policies of requests spawned on each channel.
+	retry_policies: HashMap<String, RetryPolicy>,
+	/// The longest wait a `Retry-After` header causes for requests whose retry
+	/// policy doesn't limit it.
+	max_retry_after: Duration,
+	/// The rate limits the job runner enforces.
+	rate_limits: Limits,
+	/// The circuit breaker settings the job runner enforces.
+	circuit_breaker: Option<CircuitBreaker>,
+	/// The attempt history settings, if the job runner records attempts.
+	attempt_history: Option<AttemptHistory>,
+	/// The hooks the job runner calls after attempts.
+	hooks: Notifier,
+	/// The keys requests are signed with, by name.
+	signing_keys: HashMap<String, SigningKey>,
+	/// The resolver of the secret headers of requests.
+	secrets: Secrets,
+	/// The providers of bearer tokens, by name.
+	auth_providers: HashMap<String, Provider>,
+	/// The encryption settings, if requests are encrypted in the queue.
+	encryption: Option<Encryption>,
+	/// How long an idempotency key deduplicates requests after being claimed.
+	idempotency_window: Duration,
+	/// Whether spawned requests send the trace context they were spawned in
+	/// to the server.
+	propagate_trace_context: bool,
+	/// The headers whose values are redacted from traces.
+	#[cfg(feature = "tracing")]
+	redacted_headers: HashSet<HeaderName>,
+}
+
+impl<'a> ClientBuilder<'a> {
+	/// Sets the channels to listen for jobs on. Defaults to all channels.
+	pub fn channels(mut self, channels: Channels<'a>) -> Self {
+		self.channels = channels;
+		self
+	}
+
+	/// Sets how long a single attempt to send a request may take before it's
+	/// considered failed, for requests which don't set their own timeout.
+	pub fn timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = Some(timeout);
+		self
+	}
+
+	/// Sets how long connecting to a server may take during a single attempt
+	/// to send a request, for requests which don't set their own connect
+	/// timeout.
+	pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+		self.connect_timeout = Some(connect_timeout);
+		self
+	}
+
+	/// Sets the HTTP client requests are sent with, or how it's built, e.g. to
+	/// send requests through a proxy, with custom root or client certificates,
+	/// or with a different user agent or connection pool. Requests spawned on
+	/// channels with their own client are sent with that one instead.
+	pub fn http_client(mut self, http_client: impl Into<HttpClient>) -> Self {
+		self.http_client = http_client.into();
+		self
+	}
+
+	/// Sets the HTTP client requests spawned on the given channel are sent
+	/// with, or how it's built, instead of the default one.
+	pub fn channel_http_client(
+		mut self,
+		channel: impl Into<String>,
+		http_client: impl Into<HttpClient>,
+	) -> Self {
+		self.channel_http_clients.insert(channel.into(), http_client.into());
+		self
+	}
+
+	/// Sets the retry policy of requests spawned on the given channel which
+	/// don't have a retry policy of their own.
+	pub fn retry_policy(mut self, channel: impl Into<String>, policy: RetryPolicy) -> Self {
+		self.retry_policies.insert(channel.into(), policy);
+		self
+	}
+
+	/// Sets the longest wait a `Retry-After` header can cause for requests
+	/// whose [retry policy](RetryPolicy::max_retry_after) doesn't limit it.
+	/// Defaults to an hour. Waits longer than a week are always cut short.
+	pub fn max_retry_after(mut self, max_retry_after: Duration) -> Self {
+		self.max_retry_after = max_retry_after;
+		self
+	}
+
+	/// Limits how many requests spawned on the given channel the job runner
+	/// sends. Requests which exceed the limit are deferred until a token is
+	/// available. Limits are shared with other processes using the same
+	/// database which limit the same channel. A request sent to a host with a
+	/// limit of its own only takes a token if both limits have one. Building
+	/// the client fails if the limit allows no requests.
+	pub fn channel_rate_limit(mut self, channel: impl Into<String>, limit: RateLimit) -> Self {
+		self.rate_limits.channels.insert(channel.into(), limit);
+		self
+	}
+
+	/// Limits how many requests to the given host the job runner sends,
+	/// regardless of the port. Requests which exceed the limit are deferred
+	/// until a token is available. Limits are shared with other processes
+	/// using the same database which limit the same host. Building the client
+	/// fails if the limit allows no requests.
+	pub fn host_rate_limit(mut self, host: impl Into<String>, limit: RateLimit) -> Self {
+		self.rate_limits.hosts.insert(host.into(), limit);
+		self
+	}
+
+	/// Enables circuit breaking, which stops the job runner from sending
+	/// requests to hosts which keep failing for a while. Circuits are shared
+	/// with other processes using the same database.
+	pub fn circuit_breaker(mut self, circuit_breaker: CircuitBreaker) -> Self {
+		self.circuit_breaker = Some(circuit_breaker);
+		self
+	}
+
+	/// Makes the job runner record every attempt to deliver a request in the
+	/// attempt history, which can be looked up with [`Client::attempts`]. Its
+	/// migrations have to be run with
+	/// [`migrate_attempt_history`](crate::migrate_attempt_history) first.
+	/// Attempts past the retention of the history are removed by a background
+	/// task of the client, so they're kept for longer while it isn't running.
+	pub fn attempt_history(mut self, attempt_history: AttemptHistory) -> Self {
+		self.attempt_history = Some(attempt_history);
+		self
+	}
+
+	/// Registers hooks which the job runner calls when a request was delivered,
+	/// will be retried, or is given up on.
+	pub fn hooks(mut self, hooks: impl Hooks) -> Self {
+		self.hooks = Notifier::new(hooks);
+		self
+	}
+
+	/// Registers a key under the given name, which requests referring to it
+	/// with [`Request::signing_key`] are signed with at each attempt. Every
+	/// process spawning or running jobs has to register the same keys, as the
+	/// key itself isn't stored with the request, and requests referring to a
+	/// key which isn't registered are rejected.
+	pub fn signing_key(mut self, name: impl Into<String>, key: SigningKey) -> Self {
+		self.signing_keys.insert(name.into(), key);
+		self
+	}
+
+	/// Sets the resolver which looks up the values of the
+	/// [secret headers](Request::secret_header) of requests when they're sent.
+	/// Every process running jobs has to be able to resolve the same secrets,
+	/// and requests with secret headers are rejected by clients without a
+	/// resolver. A secret the resolver doesn't know fails the request without
+	/// retrying it.
+	pub fn secret_resolver(mut self, resolver: impl SecretResolver) -> Self {
+		self.secrets = Secrets::new(resolver);
+		self
+	}
+
+	/// Registers a provider under the given name, whose tokens are sent in the
+	/// `Authorization` header of requests referring to it with
+	/// [`Request::auth_provider`]. A token is fetched when it's first needed,
+	/// cached until shortly before it expires, and refreshed when a server
+	/// rejects it with `401 Unauthorized`, in which case the request is sent
+	/// again right away without using up an attempt. Every process spawning
+	/// or running jobs has to register the same providers, and requests
+	/// referring to a provider which isn't registered are rejected.
+	pub fn auth_provider(mut self, name: impl Into<String>, provider: impl AuthProvider) -> Self {
+		self.auth_providers.insert(name.into(), Provider::new(provider));
+		self
+	}
+
+	/// Encrypts the requests spawned by the client before storing them, and
+	/// lets the job runner decrypt requests sealed with any of the keys. Every
+	/// process using the same database has to have the keys which requests
+	/// are sealed with, including the ones of dead letters.
+	pub fn encryption(mut self, encryption: Encryption) -> Self {
+		self.encryption = Some(encryption);
+		self
+	}
+
+	/// Sets how long an idempotency key deduplicates requests after the first
+	/// request with it was spawned. Defaults to 24 hours.
+	pub fn idempotency_window(mut self, idempotency_window: Duration) -> Self {
+		self.idempotency_window = idempotency_window;
+		self
+	}
+
+	/// Sets whether requests spawned in a [trace context](TraceContext) store
+	/// it with their job, so the job runner sends it to the server in
+	/// `traceparent` and `tracestate` headers, unchanged. With the `tracing`
+	/// feature, that's also the OpenTelemetry context of the span requests are
+	/// spawned in. Disabled by default.
+	pub fn propagate_trace_context(mut self, propagate_trace_context: bool) -> Self {
+		self.propagate_trace_context = propagate_trace_context;
+		self
+	}
+
+	/// Sets the headers whose values are redacted from the spans of spawned
+	/// requests, replacing the default of `Authorization`,
+	/// `Proxy-Authorization`, `Cookie`, `Set-Cookie` and `X-Api-Key`. Values
+	/// marked as [sensitive](reqwest::header::HeaderValue::set_sensitive) are
+	/// always redacted.
+	#[cfg(feature = "tracing")]
+	pub fn redacted_headers(mut self, headers: impl IntoIterator<Item = HeaderName>) -> Self {
+		self.redacted_headers = headers.into_iter().collect();
+		self
+	}
+
+	/// Constructs the client, and starts listening for jobs.
+	pub async fn build(self) -> Result<Client, ClientError> {
+		let ClientBuilder {
+			pool,
+			channels,
+			timeout,
+			connect_timeout,
+			http_client,
+			channel_http_clients,
+			retry_policies,
+			max_retry_after,
+			rate_limits,
+			circuit_breaker,
+			attempt_history,
+			hooks,
+			signing_keys,
+			secrets,
+			auth_providers,
+			encryption,
+			idempotency_window,
+			propagate_trace_context,
+			#[cfg(feature = "tracing")]
+			redacted_headers,
+		} = self;
+		#[cfg(feature = "metrics")]
+		metric::describe();
+		let mut registry = JobRegistry::new(&[job::http, job::http_response]);
+		let response_sender = ResponseSender::new();
+		let (circuit_events, _) = broadcast::channel(CIRCUIT_EVENT_CAPACITY);
+		registry.set_context(Transport::new(
+			http_client,
+			channel_http_clients,
+			timeout,
+			connect_timeout,
+		)?);
+		registry.set_context(RateLimiter::new(rate_limits)?);
+		registry.set_context(Breaker::new(circuit_breaker, circuit_events.clone()));
+		registry.set_context(Recorder::new(attempt_history));
+		registry.set_context(RetryAfterLimit(max_retry_after));
+		registry.set_context(hooks);
+		let credentials = Credentials {
+			secrets,
+			auth: Authenticator::new(auth_providers),
+			signer: Signer::new(signing_keys),
+		};
+		registry.set_context(credentials.clone());
+		let cipher = Cipher::new(encryption);
+		registry.set_context(cipher.clone());
+		registry.set_context(response_sender.clone());
+
+		let mut listener = registry.runner(&pool);
+		if let Channels::List(channels) = channels {
+			listener.set_channel_names(channels);
+		}
+
+		let mut response_listener = PgListener::connect_with(&pool).await?;
+		response_listener.listen(response::NOTIFY_CHANNEL).await?;
+		let response_listener = tokio::spawn(response::listen(
+			response_listener,
+			pool.clone(),
+			response_sender.clone(),
+		));
+		let attempt_expiry = attempt_history
+			.map(|attempt_history| tokio::spawn(history::expire(pool.clone(), attempt_history)));
+
+		Ok(Client {
+			pool,
+			listener: Some(listener.run().await?),
+			response_sender,
+			response_listener,
+			attempt_expiry,
+			retry_policies,
+			circuit_events,
+			cipher,
+			credentials,
+			idempotency_window,
+			propagate_trace_context,
+			#[cfg(feature = "tracing")]
+			redacted_headers,
+		})
+	}
 }
 
 impl std::fmt::Debug for Client {
@@ -50,18 +445,48 @@ impl Client {
 	///
 	/// It will stop running jobs when it goes out of scope, unless
 	/// `take_listener` listener is called.
-	pub async fn new(pool: PgPool, channels: Channels<'_>) -> Result<Self, sqlx::Error> {
-		let mut registry = JobRegistry::new(&[job::http, job::http_response]);
-		let response_sender = ResponseSender::new();
-		registry.set_context(reqwest::Client::new());
-		registry.set_context(response_sender.clone());
+	pub async fn new(pool: PgPool, channels: Channels<'_>) -> Result<Self, ClientError> {
+		Self::builder(pool).channels(channels).build().await
+	}
 
-		let mut listener = registry.runner(&pool);
-		if let Channels::List(channels) = channels {
-			listener.set_channel_names(channels);
+	/// Constructs a builder for a client with custom settings.
+	///
+	/// # Example
+	/// ```no_run
+	/// # async fn test(pool: sqlx::postgres::PgPool) -> Result<(), Box<dyn std::error::Error>> {
+	/// use std::time::Duration;
+	///
+	/// use requeuest::{client::Channels, Client};
+	///
+	/// let channels = Channels::List(&["my_service"]);
+	/// let builder = Client::builder(pool).channels(channels).timeout(Duration::from_secs(30));
+	/// let client = builder.build().await?;
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn builder<'a>(pool: PgPool) -> ClientBuilder<'a> {
+		ClientBuilder {
+			pool,
+			channels: Channels::All,
+			timeout: None,
+			connect_timeout: None,
+			http_client: HttpClient::default(),
+			channel_http_clients: HashMap::new(),
+			retry_policies: HashMap::new(),
+			max_retry_after: retry::DEFAULT_MAX_RETRY_AFTER,
+			rate_limits: Limits::default(),
+			circuit_breaker: None,
+			attempt_history: None,
+			hooks: Notifier::default(),
+			signing_keys: HashMap::new(),
+			secrets: Secrets::default(),
+			auth_providers: HashMap::new(),
+			encryption: None,
+			idempotency_window: DEFAULT_IDEMPOTENCY_WINDOW,
+			propagate_trace_context: false,
+			#[cfg(feature = "tracing")]
+			redacted_headers: trace::default_redacted_headers(),
 		}
-
-		Ok(Self { pool, listener: Some(listener.run().await?), response_sender })
 	}
 
 	/// Takes the job runner handle which listens for and runs spawned jobs,
@@ -92,8 +517,32 @@ impl Client {
 		}
 	}
 
+	/// Cancels the job with the given UUID, removing it from the queue. Returns
+	/// the state the job was in when it got cancelled. Tasks waiting for the
+	/// response of the job receive [`SpawnError::Cancelled`], and the
+	/// cancellation is reported to the callback of the request, if it has one.
+	pub async fn cancel(&self, id: Uuid) -> Result<Cancellation, sqlx::Error> {
+		let cancellations = self.cancel_many(&[id]).await?;
+		Ok(cancellations.into_iter().next().unwrap_or(Cancellation::Gone))
+	}
+
+	/// Cancels the jobs with the given UUIDs, removing them from the queue.
+	/// Returns the state each job was in when it got cancelled, in the same
+	/// order as the given UUIDs.
+	pub async fn cancel_many(&self, ids: &[Uuid]) -> Result<Vec<Cancellation>, sqlx::Error> {
+		let cancellations = status::cancel(&self.pool, ids, &self.cipher).await?;
+		for (id, cancellation) in ids.iter().zip(&cancellations) {
+			if *cancellation != Cancellation::Gone {
+				self.response_sender.send(*id, Err(SpawnError::Cancelled));
+			}
+		}
+		Ok(cancellations)
+	}
+
 	/// Spawns a request on the given channel. Returns the UUID of the spawned
-	/// job.
+	/// job. If the request has an idempotency key which was already used
+	/// within the idempotency window, nothing is spawned, and the UUID of the
+	/// job spawned with the key is returned instead.
 	///
 	/// # Example
 	/// ```no_run
@@ -110,21 +559,81 @@ impl Client {
 		channel: C,
 		request: &'a Request,
 	) -> Result<Uuid, SpawnError> {
-		let uuid = retrying_spawn(
-			job::http
-				.builder()
-				.set_raw_bytes(&bincode::serialize(request)?)
-				.set_channel_name(channel.into().as_ref())
-				.set_proto(default_job_proto),
-			&self.pool,
-		)
-		.await?;
-		Ok(uuid)
+		self.spawn_cfg(channel, request, |_| {}).await
+	}
+
+	/// Spawns many requests on the given channel in a single statement.
+	/// Returns the UUIDs of the spawned jobs, in the same order as the
+	/// requests. Either all requests are spawned, or none of them are.
+	/// Requests whose idempotency key was already used, including by an
+	/// earlier request of the same batch, aren't spawned, and the UUID of the
+	/// job spawned with the key is returned in their place.
+	///
+	/// The jobs are spawned with the default job settings, except that they
+	/// aren't ordered.
+	///
+	/// # Example
+	/// ```no_run
+	/// # use requeuest::{Client, Request, error::SpawnError};
+	/// # async fn example(client: Client, requests: Vec<Request>) -> Result<(), SpawnError> {
+	/// let uuids = client.spawn_batch("my_service", &requests).await?;
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub async fn spawn_batch<'r, C, I>(
+		&self,
+		channel: C,
+		requests: I,
+	) -> Result<Vec<Uuid>, SpawnError>
+	where
+		C: Into<Cow<'static, str>> + Send,
+		I: IntoIterator<Item = &'r Request>,
+	{
+		let channel = channel.into();
+		// All jobs of the batch continue the trace of spawning it
+		let trace = self.trace_context();
+		let mut jobs = Vec::new();
+		for request in requests {
+			let payload = self.traced_payload(&channel, request, trace.clone())?;
+			jobs.push(BatchJob {
+				id: Uuid::new_v4(),
+				payload,
+				idempotency_key: request.idempotency_key.as_deref(),
+			});
+		}
+		if jobs.is_empty() {
+			return Ok(Vec::new());
+		}
+
+		let spawn = async {
+			// The transaction is atomic, so it can be retried as a whole
+			loop {
+				let mut tx = self.pool.begin().await?;
+				let result = match self.insert_batch(&mut tx, &channel, &jobs).await {
+					Ok(ids) => tx.commit().await.map(|()| ids),
+					Err(e) => Err(e),
+				};
+				match result {
+					Err(e) if sqlxmq::should_retry(&e) => continue,
+					Err(e) => return Err(e.into()),
+					Ok(ids) => return Ok(ids),
+				}
+			}
+		};
+		#[cfg(feature = "tracing")]
+		let spawn = async {
+			let span = trace::batch_span(&channel, jobs.len(), trace.as_ref());
+			let result = tracing::Instrument::instrument(spawn, span.clone()).await;
+			trace::batch_spawned(&span, &result);
+			result
+		};
+		spawn.await
 	}
 
 	/// Spawn a job. Accepts a closure which lets you set custom job
 	/// parameters, such as  retry attempts should be made. By default jobs are
-	/// retried 100 000 times. See [`sqlxmq::JobBuilder`](sqlxmq::JobBuilder)
+	/// retried 100 000 times, unless their [retry policy](RetryPolicy) says
+	/// otherwise. See [`sqlxmq::JobBuilder`](sqlxmq::JobBuilder)
 	/// for available configurations. They include:
 	/// * [Number of retries](sqlxmq::JobBuilder::set_retries)
 	/// * [Initial retry backoff](sqlxmq::JobBuilder::set_retry_backoff)
@@ -146,49 +655,43 @@ impl Client {
 		request: &'a Request,
 		cfg: impl for<'b> FnOnce(&'b mut JobBuilder) + Send,
 	) -> Result<Uuid, SpawnError> {
-		let mut builder = job::http.builder();
-
+		let channel = channel.into();
+		let payload = self.payload(&channel, request)?;
+		let uuid = Uuid::new_v4();
+		let mut builder = job::http.builder_with_id(uuid);
 		let builder = builder.set_proto(default_job_proto);
+		payload.apply(builder);
 		cfg(builder);
-		let uuid = retrying_spawn(
-			builder
-				.set_channel_name(channel.into().as_ref())
-				.set_raw_bytes(&bincode::serialize(request)?),
-			&self.pool,
-		)
-		.await?;
-
-		Ok(uuid)
+		self.spawn_once(None, uuid, builder, request, &payload, false).await
 	}
 
 	/// Spawns a request and awaits until a response with an accepted status
 	/// code has been received, returning the received response. This method
 	/// will wait indefinitely until a succressful response has been received,
 	/// so be careful that your request is correctly constructed, and that you
-	/// don't inadvertently hang your program when calling this ethod.
+	/// don't inadvertently hang your program when calling this ethod. If the
+	/// request runs out of attempts, [`SpawnError::DeadLetter`] is returned,
+	/// and if the request has an expiry which passes, [`SpawnError::Expired`]
+	/// is returned.
+	///
+	/// The response is also stored in the database, so it can still be
+	/// collected with [`Client::await_response`] if this future gets dropped.
+	/// If the idempotency key of the request was already used, the response of
+	/// the job spawned with it is awaited instead, like with
+	/// [`Client::await_response`]. [`SpawnError::NotReturning`] is returned if
+	/// that job doesn't store its response.
 	pub async fn spawn_returning<'a, C: Into<Cow<'static, str>> + Send>(
 		&'a self,
 		channel: C,
 		request: &'a Request,
-	) -> Result<reqwest::Response, SpawnError> {
-		// Put a sender in the sender map so the job can use it
+	) -> Result<Response, SpawnError> {
+		let channel = channel.into();
+		let payload = self.payload(&channel, request)?;
 		let uuid = Uuid::new_v4();
-		let (sender, receiver) = oneshot::channel();
-		#[allow(clippy::unwrap_used)] // We don't handle poisoning
-		self.response_sender.lock().unwrap().insert(uuid, sender);
-
-		// Spawn the job
-		retrying_spawn(
-			job::http_response
-				.builder_with_id(uuid)
-				.set_raw_bytes(&bincode::serialize(request)?)
-				.set_channel_name(channel.into().as_ref())
-				.set_proto(default_job_proto),
-			&self.pool,
-		)
-		.await?;
-
-		Ok(receiver.await?)
+		let mut builder = job::http_response.builder_with_id(uuid);
+		let builder = builder.set_proto(default_job_proto);
+		payload.apply(builder);
+		self.spawn_and_receive(uuid, builder, request, &payload).await
 	}
 
 	/// Spawn a returning job. Accetps a closure which lets you set custom job
@@ -203,27 +706,585 @@ impl Client {
 		channel: C,
 		request: &'a Request,
 		cfg: impl for<'b> FnOnce(&'b mut JobBuilder) + Send,
-	) -> Result<reqwest::Response, SpawnError> {
-		// Put a sender in the sender map so the job can use it
+	) -> Result<Response, SpawnError> {
+		let channel = channel.into();
+		let payload = self.payload(&channel, request)?;
 		let uuid = Uuid::new_v4();
-		let (sender, receiver) = oneshot::channel();
-		#[allow(clippy::unwrap_used)] // We don't handle poisoning
-		self.response_sender.lock().unwrap().insert(uuid, sender);
+		let mut builder = job::http_response.builder_with_id(uuid);
+		let builder = builder.set_proto(default_job_proto);
+		payload.apply(builder);
+		cfg(builder);
+		self.spawn_and_receive(uuid, builder.set_ordered(false), request, &payload).await
+	}
 
-		// Spawn the job
+	/// Spawns a request without waiting for its response, which gets stored
+	/// once received. Returns the UUID of the spawned job, which any process
+	/// can collect the response with using [`Client::await_response`] or
+	/// [`Client::fetch_response`].
+	pub async fn spawn_storing<'a, C: Into<Cow<'static, str>> + Send>(
+		&'a self,
+		channel: C,
+		request: &'a Request,
+	) -> Result<Uuid, SpawnError> {
+		let channel = channel.into();
+		let payload = self.payload(&channel, request)?;
+		let uuid = Uuid::new_v4();
 		let mut builder = job::http_response.builder_with_id(uuid);
 		let builder = builder.set_proto(default_job_proto);
+		payload.apply(builder);
+		self.spawn_once(None, uuid, builder, request, &payload, true).await
+	}
+
+	/// Spawns a request on the given channel inside of the given transaction,
+	/// so it's only queued if the transaction commits. Returns the UUID of the
+	/// spawned job.
+	///
+	/// # Example
+	/// ```no_run
+	/// # use requeuest::{Client, Request, error::SpawnError};
+	/// # async fn example(client: Client, request: Request) -> Result<(), SpawnError> {
+	/// let mut tx = client.pool().begin().await?;
+	/// // ... make changes which the request should only be sent for if they commit
+	/// client.spawn_in(&mut tx, "my_service", &request).await?;
+	/// tx.commit().await?;
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub async fn spawn_in<'a, C: Into<Cow<'static, str>> + Send>(
+		&'a self,
+		tx: &mut Transaction<'_, Postgres>,
+		channel: C,
+		request: &'a Request,
+	) -> Result<Uuid, SpawnError> {
+		self.spawn_in_cfg(tx, channel, request, |_| {}).await
+	}
+
+	/// Spawns a request inside of the given transaction, so it's only queued
+	/// if the transaction commits. Accepts a closure which lets you set custom
+	/// job parameters, like [`Client::spawn_cfg`].
+	pub async fn spawn_in_cfg<'a, C: Into<Cow<'static, str>> + Send>(
+		&'a self,
+		tx: &mut Transaction<'_, Postgres>,
+		channel: C,
+		request: &'a Request,
+		cfg: impl for<'b> FnOnce(&'b mut JobBuilder) + Send,
+	) -> Result<Uuid, SpawnError> {
+		let channel = channel.into();
+		let payload = self.payload(&channel, request)?;
+		let uuid = Uuid::new_v4();
+		let mut builder = job::http.builder_with_id(uuid);
+		let builder = builder.set_proto(default_job_proto);
+		payload.apply(builder);
 		cfg(builder);
-		retrying_spawn(
-			builder
-				.set_raw_bytes(&bincode::serialize(request)?)
-				.set_channel_name(channel.into().as_ref())
-				.set_ordered(false),
-			&self.pool,
+		self.spawn_once(Some(tx), uuid, builder, request, &payload, false).await
+	}
+
+	/// Spawns a request inside of the given transaction without waiting for
+	/// its response, which gets stored once received. The request is only
+	/// queued if the transaction commits, after which its response can be
+	/// collected with [`Client::await_response`] using the returned UUID.
+	pub async fn spawn_storing_in<'a, C: Into<Cow<'static, str>> + Send>(
+		&'a self,
+		tx: &mut Transaction<'_, Postgres>,
+		channel: C,
+		request: &'a Request,
+	) -> Result<Uuid, SpawnError> {
+		let channel = channel.into();
+		let payload = self.payload(&channel, request)?;
+		let uuid = Uuid::new_v4();
+		let mut builder = job::http_response.builder_with_id(uuid);
+		let builder = builder.set_proto(default_job_proto);
+		payload.apply(builder);
+		self.spawn_once(Some(tx), uuid, builder, request, &payload, true).await
+	}
+
+	/// Waits until the returning job with the given UUID has received a
+	/// response, returning it. Returns [`SpawnError::DeadLetter`] if the
+	/// request ran out of attempts, and [`SpawnError::Missing`] if the job
+	/// doesn't exist anymore without having left a response behind.
+	///
+	/// Only one task per client can wait for the response to a given job at a
+	/// time.
+	pub async fn await_response(&self, id: Uuid) -> Result<Response, SpawnError> {
+		// Register as waiting before looking the job up, so a response arriving
+		// in between isn't missed
+		let receiver = self.response_sender.register(id);
+		match response::lookup(&self.pool, id).await {
+			Ok(None) => receiver.await?,
+			Ok(Some(result)) => {
+				self.response_sender.remove(id);
+				result
+			}
+			Err(error) => {
+				self.response_sender.remove(id);
+				Err(error)
+			}
+		}
+	}
+
+	/// Fetches the stored response of the returning job with the given UUID.
+	/// Returns `None` if no response has been received yet.
+	pub async fn fetch_response(&self, id: Uuid) -> Result<Option<Response>, SpawnError> {
+		response::fetch(&self.pool, id).await
+	}
+
+	/// Deletes all stored responses which were received before the given point
+	/// in time. Returns the number of deleted responses.
+	pub async fn purge_responses(&self, before: DateTime<Utc>) -> Result<u64, sqlx::Error> {
+		let result = sqlx::query("DELETE FROM requeuest_responses WHERE completed_at < $1")
+			.bind(before)
+			.execute(&self.pool)
+			.await?;
+		Ok(result.rows_affected())
+	}
+
+	/// Returns the trace context requests spawned now are stored with, which is
+	/// the current context if it's propagated or recorded in the spans of the
+	/// request.
+	fn trace_context(&self) -> Option<TraceContext> {
+		TraceContext::current()
+			.filter(|_| self.propagate_trace_context || cfg!(feature = "tracing"))
+	}
+
+	/// Serializes a request to be spawned on the given channel, in the current
+	/// trace context.
+	fn payload<'c>(&self, channel: &'c str, request: &Request) -> Result<Payload<'c>, SpawnError> {
+		self.traced_payload(channel, request, self.trace_context())
+	}
+
+	/// Serializes a request to be spawned on the given channel in the given
+	/// trace context, applying the channel's retry policy if the request
+	/// doesn't have its own. Fails if the request can never be sent.
+	fn traced_payload<'c>(
+		&self,
+		channel: &'c str,
+		request: &Request,
+		trace: Option<TraceContext>,
+	) -> Result<Payload<'c>, SpawnError> {
+		if let (true, Some(key)) = (request.forward_idempotency_key, &request.idempotency_key) {
+			HeaderValue::from_str(key).map_err(SpawnError::InvalidIdempotencyKey)?;
+		}
+		self.credentials.check(request)?;
+		let policy = request.retry_policy.or_else(|| self.retry_policies.get(channel).copied());
+		let bytes = match policy {
+			Some(policy) if request.retry_policy.is_none() => {
+				let mut request = request.clone();
+				request.retry_policy = Some(policy);
+				self.cipher.encode(&request)?
+			}
+			_ => self.cipher.encode(request)?,
+		};
+		let retries = policy
+			.and_then(|policy| policy.max_retries)
+			.map(|retries| usize::try_from(retries).unwrap_or(usize::MAX));
+		let json = trace.as_ref().map(|trace| trace.to_json(self.propagate_trace_context));
+		Ok(Payload { channel, bytes, retries, json, trace })
+	}
+
+	/// Claims the idempotency keys of a batch of jobs, and inserts the jobs
+	/// whose keys weren't already claimed in a single statement. Returns the
+	/// UUIDs of the jobs, or of the jobs which claimed their keys earlier.
+	async fn insert_batch(
+		&self,
+		tx: &mut Transaction<'_, Postgres>,
+		channel: &str,
+		jobs: &[BatchJob<'_>],
+	) -> Result<Vec<Uuid>, sqlx::Error> {
+		// Jobs sharing a key within the batch all resolve to the first of them
+		let mut claims = HashMap::new();
+		for job in jobs {
+			if let Some(key) = job.idempotency_key {
+				claims.entry(key).or_insert(job.id);
+			}
+		}
+		let (keys, claim_ids): (Vec<&str>, Vec<Uuid>) = claims.iter().unzip();
+		let claimed =
+			idempotency::claim(tx, &keys, &claim_ids, false, self.idempotency_window).await?;
+
+		let mut uuids = Vec::with_capacity(jobs.len());
+		let (mut ids, mut retries, mut payloads, mut json) =
+			(Vec::new(), Vec::new(), Vec::new(), Vec::new());
+		for job in jobs {
+			let uuid = job
+				.idempotency_key
+				.and_then(|key| claimed.get(key).or_else(|| claims.get(key)))
+				.copied()
+				.unwrap_or(job.id);
+			uuids.push(uuid);
+			if uuid == job.id {
+				let job_retries = job.payload.retries.unwrap_or(DEFAULT_RETRIES);
+				ids.push(job.id);
+				retries.push(i32::try_from(job_retries).unwrap_or(i32::MAX));
+				payloads.push(job.payload.bytes.as_slice());
+				json.push(job.payload.json.as_deref());
+			}
+		}
+		if ids.is_empty() {
+			return Ok(uuids);
+		}
+
+		let retry_backoff = i64::try_from(DEFAULT_RETRY_BACKOFF.as_millis()).unwrap_or(i64::MAX);
+		sqlx::query(
+			"SELECT mq_insert(ARRAY(
+				SELECT (
+					id, INTERVAL '0', retries, $4 * INTERVAL '1 millisecond', $5, '', NULL, FALSE,
+					$6, payload_json::JSONB, payload_bytes
+				)::mq_new_t
+				FROM UNNEST($1::UUID[], $2::INT[], $3::BYTEA[], $7::TEXT[])
+					AS new_messages(id, retries, payload_bytes, payload_json)
+			))",
 		)
+		.bind(&ids)
+		.bind(&retries)
+		.bind(&payloads)
+		.bind(retry_backoff)
+		.bind(channel)
+		.bind(job::http.name())
+		.bind(&json)
+		.execute(&mut **tx)
 		.await?;
-		Ok(receiver.await?)
+		Ok(uuids)
+	}
+
+	/// Spawns a returning job for the given request, and waits for its
+	/// response. If the idempotency key of the request was already claimed,
+	/// waits for the response of the job which claimed it instead, unless that
+	/// job doesn't store its response.
+	async fn spawn_and_receive(
+		&self,
+		uuid: Uuid,
+		job: &JobBuilder<'_>,
+		request: &Request,
+		payload: &Payload<'_>,
+	) -> Result<Response, SpawnError> {
+		let receiver = self.response_sender.register(uuid);
+		match self.spawn_once(None, uuid, job, request, payload, true).await {
+			Ok(id) if id == uuid => self.receive(uuid, receiver, request.expires_at).await,
+			Ok(id) => {
+				self.response_sender.remove(uuid);
+				if idempotency::is_returning(&self.pool, id).await? == Some(false) {
+					return Err(SpawnError::NotReturning(id));
+				}
+				self.await_response(id).await
+			}
+			Err(error) => {
+				self.response_sender.remove(uuid);
+				Err(error)
+			}
+		}
+	}
+
+	/// Spawns the given job with the given UUID for the given request, inside
+	/// of the given transaction if there is one, unless the idempotency key of
+	/// the request was already claimed within the idempotency window. The job
+	/// claims the key as a returning job if `returning` is set. Returns the
+	/// UUID of the job which claimed the key.
+	#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+	async fn spawn_once(
+		&self,
+		tx: Option<&mut Transaction<'_, Postgres>>,
+		uuid: Uuid,
+		job: &JobBuilder<'_>,
+		request: &Request,
+		payload: &Payload<'_>,
+		returning: bool,
+	) -> Result<Uuid, SpawnError> {
+		let spawn = async {
+			match tx {
+				Some(tx) => self.claim_and_spawn(tx, uuid, job, request, returning).await,
+				None if request.idempotency_key.is_none() => retrying_spawn(job, &self.pool).await,
+				None => {
+					let mut tx = self.pool.begin().await?;
+					let id = self.claim_and_spawn(&mut tx, uuid, job, request, returning).await?;
+					tx.commit().await?;
+					Ok(id)
+				}
+			}
+		};
+		#[cfg(feature = "tracing")]
+		let spawn = async {
+			let span = trace::spawn_span(
+				uuid,
+				payload.channel,
+				request,
+				payload.trace.as_ref(),
+				&self.redacted_headers,
+			);
+			let result = tracing::Instrument::instrument(spawn, span.clone()).await;
+			trace::spawned(&span, uuid, &result);
+			result
+		};
+		spawn.await
 	}
+
+	/// Claims the idempotency key of the given request for the given job
+	/// inside of the given transaction, and spawns the job unless the key was
+	/// already claimed within the idempotency window. Returns the UUID of the
+	/// job which claimed the key.
+	async fn claim_and_spawn(
+		&self,
+		tx: &mut Transaction<'_, Postgres>,
+		uuid: Uuid,
+		job: &JobBuilder<'_>,
+		request: &Request,
+		returning: bool,
+	) -> Result<Uuid, SpawnError> {
+		if let Some(key) = &request.idempotency_key {
+			let window = self.idempotency_window;
+			let claimed = idempotency::claim(tx, &[key], &[uuid], returning, window).await?;
+			if let Some(id) = claimed.get(key) {
+				return Ok(*id);
+			}
+		}
+		retrying_spawn_in(job, tx).await
+	}
+
+	/// Waits for the response of a returning job, giving up once the request
+	/// has expired.
+	async fn receive(
+		&self,
+		uuid: Uuid,
+		receiver: oneshot::Receiver<Result<Response, SpawnError>>,
+		expires_at: Option<DateTime<Utc>>,
+	) -> Result<Response, SpawnError> {
+		let Some(expires_at) = expires_at else {
+			return receiver.await?;
+		};
+		let remaining = (expires_at - Utc::now()).to_std().unwrap_or_default();
+		if let Ok(result) = tokio::time::timeout(remaining, receiver).await {
+			return result?;
+		}
+		self.response_sender.remove(uuid);
+		Err(SpawnError::Expired)
+	}
+
+	/// Gets the status of the job with the given UUID. Returns `None` if no job
+	/// with the UUID is known.
+	///
+	/// # Example
+	/// ```no_run
+	/// # use requeuest::{Client, Request, error::SpawnError};
+	/// # async fn example(client: Client, request: Request) -> Result<(), SpawnError> {
+	/// use requeuest::status::JobState;
+	///
+	/// let uuid = client.spawn("my_service", &request).await?;
+	/// let status = client.status(uuid).await?.map(|status| status.state);
+	/// let delivered = matches!(status, Some(JobState::Completed(_)));
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub async fn status(&self, id: Uuid) -> Result<Option<JobStatus>, sqlx::Error> {
+		status::fetch(&self.pool, id).await
+	}
+
+	/// Deletes all idempotency keys whose idempotency window has passed.
+	/// Returns the number of deleted keys.
+	pub async fn purge_idempotency_keys(&self) -> Result<u64, sqlx::Error> {
+		let window = i64::try_from(self.idempotency_window.as_millis()).unwrap_or(i64::MAX);
+		let result = sqlx::query(
+			"DELETE FROM requeuest_idempotency_keys
+			WHERE created_at <= NOW() - $1 * INTERVAL '1 millisecond'",
+		)
+		.bind(window)
+		.execute(&self.pool)
+		.await?;
+		Ok(result.rows_affected())
+	}
+
+	/// Counts the jobs queued on each channel by the state they're in, along
+	/// with the age of the oldest one. Only channels with queued jobs are
+	/// listed.
+	///
+	/// # Example
+	/// ```no_run
+	/// # async fn example(client: requeuest::Client) -> Result<(), sqlx::Error> {
+	/// let stats = client.queue_stats().await?;
+	/// let pending: u64 = stats.iter().map(|stats| stats.pending).sum();
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub async fn queue_stats(&self) -> Result<Vec<QueueStats>, sqlx::Error> {
+		status::queue_stats(&self.pool).await
+	}
+
+	/// Deletes the recorded outcomes of all jobs which have left the queue, and
+	/// whose last attempt was started before the given point in time. Returns
+	/// the number of deleted outcomes.
+	pub async fn purge_outcomes(&self, before: DateTime<Utc>) -> Result<u64, sqlx::Error> {
+		let result = sqlx::query(
+			"DELETE FROM requeuest_outcomes
+			WHERE last_attempt_at < $1
+			AND NOT EXISTS(SELECT 1 FROM mq_msgs WHERE mq_msgs.id = requeuest_outcomes.id)",
+		)
+		.bind(before)
+		.execute(&self.pool)
+		.await?;
+		Ok(result.rows_affected())
+	}
+
+	/// Gets the recorded attempts to deliver the request of the job with the
+	/// given UUID, in the order they were made. Attempts are only recorded if
+	/// the client was built with
+	/// [`ClientBuilder::attempt_history`](ClientBuilder::attempt_history).
+	///
+	/// # Example
+	/// ```no_run
+	/// # async fn example(client: requeuest::Client, id: uuid::Uuid) -> Result<(), sqlx::Error> {
+	/// let attempts = client.attempts(id).await?;
+	/// let outcomes: Vec<_> = attempts.iter().map(|attempt| &attempt.outcome).collect();
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub async fn attempts(&self, id: Uuid) -> Result<Vec<AttemptRecord>, sqlx::Error> {
+		history::fetch(&self.pool, id).await
+	}
+
+	/// Deletes the recorded attempts which were started before the given point
+	/// in time, regardless of the retention of the attempt history. Returns the
+	/// number of deleted attempts.
+	pub async fn purge_attempts(&self, before: DateTime<Utc>) -> Result<u64, sqlx::Error> {
+		let result = sqlx::query("DELETE FROM requeuest_attempts WHERE started_at < $1")
+			.bind(before)
+			.execute(&self.pool)
+			.await?;
+		Ok(result.rows_affected())
+	}
+
+	/// Gets the circuit of the given host. Returns `None` if no request to the
+	/// host has failed since circuit breaking was enabled.
+	pub async fn circuit(&self, host: &str) -> Result<Option<Circuit>, sqlx::Error> {
+		circuit::fetch(&self.pool, host).await
+	}
+
+	/// Lists the circuits of all hosts which any request has failed for.
+	pub async fn circuits(&self) -> Result<Vec<Circuit>, sqlx::Error> {
+		circuit::fetch_all(&self.pool).await
+	}
+
+	/// Subscribes to the circuit events caused by the job runner of this
+	/// client, such as circuits tripping and closing again. Events caused by
+	/// other processes aren't received.
+	///
+	/// # Example
+	/// ```no_run
+	/// # async fn example(client: requeuest::Client) -> Result<(), Box<dyn std::error::Error>> {
+	/// use requeuest::circuit::CircuitEvent;
+	///
+	/// let mut events = client.circuit_events();
+	/// let event = events.recv().await?;
+	/// let tripped = matches!(event, CircuitEvent::Tripped { .. });
+	/// # Ok(())
+	/// # }
+	/// ```
+	#[must_use]
+	pub fn circuit_events(&self) -> broadcast::Receiver<CircuitEvent> {
+		self.circuit_events.subscribe()
+	}
+
+	/// Lists the requests in the given channels which ran out of attempts
+	/// without being delivered, oldest failures first. Fails with
+	/// [`SpawnError::Encryption`] while one of them was sealed with a key the
+	/// client doesn't have.
+	pub async fn dead_letters(
+		&self,
+		channels: Channels<'_>,
+	) -> Result<Vec<DeadLetter>, SpawnError> {
+		sqlx::query(
+			"SELECT * FROM requeuest_dead_letters
+			WHERE $1::TEXT[] IS NULL OR channel_name = ANY($1)
+			ORDER BY failed_at ASC",
+		)
+		.bind(channels.filter())
+		.fetch_all(&self.pool)
+		.await?
+		.iter()
+		.map(|row| DeadLetter::from_row(row, &self.cipher))
+		.collect()
+	}
+
+	/// Gets the dead letter of the job with the given UUID, if the job has run
+	/// out of attempts.
+	pub async fn dead_letter(&self, id: Uuid) -> Result<Option<DeadLetter>, SpawnError> {
+		sqlx::query("SELECT * FROM requeuest_dead_letters WHERE id = $1")
+			.bind(id)
+			.fetch_optional(&self.pool)
+			.await?
+			.as_ref()
+			.map(|row| DeadLetter::from_row(row, &self.cipher))
+			.transpose()
+	}
+
+	/// Removes a request from the dead letter table and spawns it again on its
+	/// original channel with the default job settings. Returns the UUID of the
+	/// new job, or `None` if there was no dead letter with the given UUID.
+	///
+	/// Requests originally spawned with one of the `spawn_returning` methods
+	/// are requeued as regular jobs, since nothing is awaiting their response
+	/// anymore. The new job takes over the idempotency key of the request, if
+	/// it has one.
+	pub async fn requeue_dead_letter(&self, id: Uuid) -> Result<Option<Uuid>, SpawnError> {
+		let mut tx = self.pool.begin().await?;
+		let Some(letter) = dead_letter::take(&mut tx, id, &self.cipher).await? else {
+			return Ok(None);
+		};
+		// The new job claims the idempotency key of the request instead
+		idempotency::release(&mut tx, id).await?;
+		let uuid = self.spawn_in(&mut tx, letter.channel, &letter.request).await?;
+		tx.commit().await?;
+		Ok(Some(uuid))
+	}
+
+	/// Deletes all dead letters from the given set of channels. Returns the
+	/// number of deleted dead letters.
+	pub async fn purge_dead_letters(&self, channels: Channels<'_>) -> Result<u64, sqlx::Error> {
+		let result = sqlx::query(
+			"DELETE FROM requeuest_dead_letters WHERE $1::TEXT[] IS NULL OR channel_name = ANY($1)",
+		)
+		.bind(channels.filter())
+		.execute(&self.pool)
+		.await?;
+		Ok(result.rows_affected())
+	}
+}
+
+/// A request serialized to be spawned on a channel.
+struct Payload<'a> {
+	/// The channel the request is spawned on.
+	channel: &'a str,
+	/// The serialized request.
+	bytes: Vec<u8>,
+	/// The number of retries the retry policy of the request allows, if it
+	/// limits them.
+	retries: Option<usize>,
+	/// The JSON payload stored with the job, if there is one.
+	json: Option<String>,
+	/// The trace context the request is spawned in, which is stored with the
+	/// job.
+	#[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+	trace: Option<TraceContext>,
+}
+
+impl<'a> Payload<'a> {
+	/// Sets the channel, request and retries of the given job.
+	fn apply(&'a self, builder: &mut JobBuilder<'a>) {
+		builder.set_channel_name(self.channel).set_raw_bytes(&self.bytes);
+		if let Some(retries) = self.retries {
+			builder.set_retries(retries);
+		}
+		if let Some(json) = &self.json {
+			builder.set_raw_json(json);
+		}
+	}
+}
+
+/// A job of a batch being spawned.
+struct BatchJob<'a> {
+	/// The UUID the job is spawned with.
+	id: Uuid,
+	/// The serialized request of the job.
+	payload: Payload<'a>,
+	/// The idempotency key of the request.
+	idempotency_key: Option<&'a str>,
 }
 
 /// Retry spawning a job if we receive certain database errors.
@@ -239,3 +1300,26 @@ async fn retrying_spawn<'a>(job: &'a JobBuilder<'a>, pool: &PgPool) -> Result<Uu
 	};
 	Ok(uuid)
 }
+
+/// Retry spawning a job inside of the given transaction if we receive certain
+/// database errors. Each try is made in a savepoint, so failed tries don't
+/// abort the transaction.
+async fn retrying_spawn_in<'a>(
+	job: &'a JobBuilder<'a>,
+	tx: &mut Transaction<'_, Postgres>,
+) -> Result<Uuid, SpawnError> {
+	loop {
+		let mut savepoint = Connection::begin(&mut **tx).await?;
+		match job.spawn(&mut *savepoint).await {
+			Err(e) if sqlxmq::should_retry(&e) => savepoint.rollback().await?,
+			Err(e) => {
+				savepoint.rollback().await?;
+				return Err(e.into());
+			}
+			Ok(uuid) => {
+				savepoint.commit().await?;
+				return Ok(uuid);
+			}
+		}
+	}
+}
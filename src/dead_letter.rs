@@ -0,0 +1,134 @@
+//! Requests which exhausted all of their attempts without being delivered are
+//! moved to a dead letter table, from where they can be inspected, requeued or
+//! purged through the [`Client`](crate::Client).
+
+use chrono::{DateTime, Utc};
+use sqlx::{postgres::PgRow, Postgres, Row, Transaction};
+use sqlxmq::CurrentJob;
+use uuid::Uuid;
+
+use crate::{
+	callback::Followup, encryption::Cipher, error::SpawnError, request::Request, response,
+	status::Outcome,
+};
+
+/// A request which ran out of attempts without being delivered.
+#[derive(Debug)]
+pub struct DeadLetter {
+	/// The UUID of the job which ran the request.
+	pub id: Uuid,
+	/// The channel the request was spawned on.
+	pub channel: String,
+	/// The request which failed to be delivered.
+	pub request: Request,
+	/// The outcome of the last attempt.
+	pub last_outcome: Option<Outcome>,
+	/// When the request was originally spawned.
+	pub created_at: DateTime<Utc>,
+	/// When the request ran out of attempts.
+	pub failed_at: DateTime<Utc>,
+}
+
+impl DeadLetter {
+	/// Constructs a dead letter from a row of the dead letter table, opening
+	/// its request with the given cipher.
+	pub(crate) fn from_row(row: &PgRow, cipher: &Cipher) -> Result<Self, SpawnError> {
+		Ok(Self {
+			id: row.try_get("id")?,
+			channel: row.try_get("channel_name")?,
+			request: cipher.decode(row.try_get("payload_bytes")?)?,
+			last_outcome: Outcome::from_columns(
+				row.try_get("last_status")?,
+				row.try_get("last_error")?,
+			),
+			created_at: row.try_get("created_at")?,
+			failed_at: row.try_get("failed_at")?,
+		})
+	}
+}
+
+/// Moves the job to the dead letter table if it has no attempts left after an
+/// attempt with the given outcome, removing it from the queue and spawning the
+/// report to its callback, if it has one. Returns whether the job was moved.
+pub(crate) async fn bury_if_exhausted(
+	job: &mut CurrentJob,
+	outcome: &Outcome,
+	followup: Option<&Followup>,
+) -> Result<bool, sqlx::Error> {
+	move_to_dead_letters(job, outcome, followup, true).await
+}
+
+/// Moves the job to the dead letter table after an attempt with the given
+/// outcome, regardless of how many attempts it has left, removing it from the
+/// queue and spawning the report to its callback, if it has one. Returns
+/// whether the job was moved.
+pub(crate) async fn bury(
+	job: &mut CurrentJob,
+	outcome: &Outcome,
+	followup: Option<&Followup>,
+) -> Result<bool, sqlx::Error> {
+	move_to_dead_letters(job, outcome, followup, false).await
+}
+
+/// Moves the job to the dead letter table, either unconditionally or only if
+/// it has no attempts left.
+async fn move_to_dead_letters(
+	job: &mut CurrentJob,
+	outcome: &Outcome,
+	followup: Option<&Followup>,
+	exhausted_only: bool,
+) -> Result<bool, sqlx::Error> {
+	let (status, error) = outcome.columns();
+
+	let mut tx = job.pool().begin().await?;
+	let buried = sqlx::query(
+		"INSERT INTO requeuest_dead_letters
+			(id, name, channel_name, payload_bytes, last_status, last_error, created_at)
+		SELECT
+			mq_msgs.id,
+			mq_payloads.name,
+			mq_msgs.channel_name,
+			mq_payloads.payload_bytes,
+			$2,
+			$3,
+			COALESCE(mq_msgs.created_at, NOW())
+		FROM mq_msgs
+		INNER JOIN mq_payloads ON mq_payloads.id = mq_msgs.id
+		WHERE mq_msgs.id = $1
+		AND (NOT $4 OR mq_msgs.attempts = 0)
+		AND mq_payloads.payload_bytes IS NOT NULL",
+	)
+	.bind(job.id())
+	.bind(status)
+	.bind(error)
+	.bind(exhausted_only)
+	.execute(&mut *tx)
+	.await?
+	.rows_affected()
+		> 0;
+
+	if buried {
+		response::notify(&mut *tx, job.id()).await?;
+		if let Some(followup) = followup {
+			followup.spawn(&mut *tx).await?;
+		}
+		job.complete_with_transaction(tx).await?;
+	}
+	Ok(buried)
+}
+
+/// Removes a dead letter inside of the given transaction, returning it opened
+/// with the given cipher if it existed.
+pub(crate) async fn take(
+	tx: &mut Transaction<'_, Postgres>,
+	id: Uuid,
+	cipher: &Cipher,
+) -> Result<Option<DeadLetter>, SpawnError> {
+	sqlx::query("DELETE FROM requeuest_dead_letters WHERE id = $1 RETURNING *")
+		.bind(id)
+		.fetch_optional(&mut **tx)
+		.await?
+		.as_ref()
+		.map(|row| DeadLetter::from_row(row, cipher))
+		.transpose()
+}
@@ -0,0 +1,178 @@
+//! At-rest encryption of the requests stored in the queue. When the client is
+//! built with
+//! [`ClientBuilder::encryption`](crate::client::ClientBuilder::encryption),
+//! requests are serialized and then sealed with AES-256-GCM before being
+//! stored, along with the ID of the key they were sealed with. Keys can be
+//! rotated by making a new key the current one and keeping the previous ones
+//! for decryption, until every request sealed with them has been delivered or
+//! purged. Requests stored without encryption can still be read.
+//!
+//! A sealed payload consists of a magic prefix, the big endian key ID, the
+//! nonce and the ciphertext. The prefix and key ID are authenticated along
+//! with the ciphertext.
+
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use aes_gcm::{
+	aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+	Aes256Gcm, Key, Nonce,
+};
+
+use crate::{
+	error::{EncryptionError, SpawnError},
+	request::Request,
+};
+
+/// The prefix of sealed payloads. Read as the length of the URL a plain
+/// payload starts with, it would exceed any URL which can actually be stored.
+const MAGIC: &[u8] = b"RQENC1";
+/// The length of the nonce of AES-256-GCM.
+const NONCE_LEN: usize = 12;
+
+/// The keys requests are encrypted with.
+///
+/// # Example
+/// ```
+/// use requeuest::encryption::Encryption;
+///
+/// # let (old_key, new_key) = ([1; 32], [2; 32]);
+/// // Seal new requests with key 2, and keep reading the ones sealed with key 1
+/// let encryption = Encryption::new(2, new_key).decryption_key(1, old_key);
+/// ```
+#[derive(Clone)]
+#[must_use]
+pub struct Encryption {
+	/// The ID of the key new requests are sealed with.
+	current: u32,
+	/// The ciphers of all keys, by their ID.
+	ciphers: HashMap<u32, Aes256Gcm>,
+}
+
+impl Encryption {
+	/// Constructs the encryption settings, which seal new requests with the
+	/// given 256 bit key under the given ID.
+	pub fn new(key_id: u32, key: [u8; 32]) -> Self {
+		Self { current: key_id, ciphers: HashMap::new() }.decryption_key(key_id, key)
+	}
+
+	/// Adds a key which requests sealed with the given ID are opened with,
+	/// e.g. the previous key after a rotation.
+	pub fn decryption_key(mut self, key_id: u32, key: [u8; 32]) -> Self {
+		self.ciphers.insert(key_id, Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)));
+		self
+	}
+}
+
+impl fmt::Debug for Encryption {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Encryption")
+			.field("current", &self.current)
+			.field("key_ids", &self.ciphers.keys().collect::<Vec<_>>())
+			.finish()
+	}
+}
+
+/// Serializes requests, and seals them if encryption is enabled.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Cipher(Option<Arc<Encryption>>);
+
+impl Cipher {
+	/// Constructs a cipher with the given settings, which doesn't encrypt
+	/// anything if there are none.
+	pub fn new(encryption: Option<Encryption>) -> Self {
+		Self(encryption.map(Arc::new))
+	}
+
+	/// Serializes the given request, and seals it with the current key.
+	pub fn encode(&self, request: &Request) -> Result<Vec<u8>, SpawnError> {
+		let bytes = request.to_payload()?;
+		let Some(encryption) = &self.0 else {
+			return Ok(bytes);
+		};
+		let cipher = encryption
+			.ciphers
+			.get(&encryption.current)
+			.ok_or(EncryptionError::UnknownKey(encryption.current))?;
+		let mut sealed = MAGIC.to_vec();
+		sealed.extend_from_slice(&encryption.current.to_be_bytes());
+		let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+		let ciphertext = cipher
+			.encrypt(&nonce, Payload { msg: &bytes, aad: &sealed })
+			.map_err(|_| EncryptionError::Failed)?;
+		sealed.extend_from_slice(&nonce);
+		sealed.extend_from_slice(&ciphertext);
+		Ok(sealed)
+	}
+
+	/// Opens the given payload if it's sealed, and deserializes the request.
+	pub fn decode(&self, bytes: &[u8]) -> Result<Request, SpawnError> {
+		if !bytes.starts_with(MAGIC) {
+			return Ok(Request::from_payload(bytes)?);
+		}
+		let header_len = MAGIC.len() + 4;
+		if bytes.len() < header_len + NONCE_LEN {
+			return Err(EncryptionError::Malformed.into());
+		}
+		let (header, rest) = bytes.split_at(header_len);
+		let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+		let mut key_id = [0; 4];
+		key_id.copy_from_slice(&header[MAGIC.len()..]);
+		let key_id = u32::from_be_bytes(key_id);
+		let cipher = self
+			.0
+			.as_ref()
+			.and_then(|encryption| encryption.ciphers.get(&key_id))
+			.ok_or(EncryptionError::UnknownKey(key_id))?;
+		let bytes = cipher
+			.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: header })
+			.map_err(|_| EncryptionError::Failed)?;
+		Ok(Request::from_payload(&bytes)?)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	#![allow(clippy::unwrap_used)]
+	use super::{Cipher, Encryption};
+	use crate::{
+		error::{EncryptionError, SpawnError},
+		request::Request,
+	};
+
+	#[test]
+	fn rotation() {
+		let request =
+			Request::post("https://example.com/", b"personal data".to_vec()).unwrap().build();
+		let old = Cipher::new(Some(Encryption::new(1, [1; 32])));
+		let new = Cipher::new(Some(Encryption::new(2, [2; 32]).decryption_key(1, [1; 32])));
+
+		let sealed = old.encode(&request).unwrap();
+		assert!(!sealed.windows(13).any(|window| window == b"personal data"), "Body was stored");
+		assert_eq!(new.decode(&sealed).unwrap().body, request.body);
+
+		let sealed = new.encode(&request).unwrap();
+		assert_eq!(new.decode(&sealed).unwrap().body, request.body);
+		assert!(matches!(
+			old.decode(&sealed),
+			Err(SpawnError::Encryption(EncryptionError::UnknownKey(2)))
+		));
+		assert!(matches!(
+			Cipher::default().decode(&sealed),
+			Err(SpawnError::Encryption(EncryptionError::UnknownKey(2)))
+		));
+
+		let plain = Cipher::default().encode(&request).unwrap();
+		assert_eq!(new.decode(&plain).unwrap().body, request.body);
+
+		let mut tampered = sealed;
+		*tampered.last_mut().unwrap() ^= 1;
+		assert!(matches!(
+			new.decode(&tampered),
+			Err(SpawnError::Encryption(EncryptionError::Failed))
+		));
+		assert!(matches!(
+			new.decode(&tampered[..12]),
+			Err(SpawnError::Encryption(EncryptionError::Malformed))
+		));
+	}
+}
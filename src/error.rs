@@ -29,6 +29,149 @@ impl std::fmt::Display for JobError {
 
 impl std::error::Error for JobError {}
 
+/// An error that can occur when signing a request.
+#[derive(Debug)]
+pub enum SigningError {
+	/// The request refers to a signing key which isn't registered on the
+	/// client.
+	UnknownKey(String),
+	/// The signing scheme failed to sign the request.
+	Scheme(crate::signing::SchemeError),
+}
+
+impl std::error::Error for SigningError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match *self {
+			SigningError::UnknownKey(_) => None,
+			SigningError::Scheme(ref e) => Some(e.as_ref()),
+		}
+	}
+}
+
+impl std::fmt::Display for SigningError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			SigningError::UnknownKey(name) => write!(f, "Unknown signing key: {}", name),
+			SigningError::Scheme(e) => write!(f, "Signing error: {}", e),
+		}
+	}
+}
+
+/// An error that can occur when resolving the secret headers of a request.
+#[derive(Debug)]
+pub enum SecretError {
+	/// The request refers to a secret which the client's resolver doesn't
+	/// know, or the client has no resolver.
+	Unknown(String),
+	/// The resolver failed to look up a secret.
+	Resolver(crate::secret::ResolverError),
+	/// The name of a secret header isn't a valid header name.
+	InvalidName(reqwest::header::InvalidHeaderName),
+}
+
+impl std::error::Error for SecretError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match *self {
+			SecretError::Unknown(_) => None,
+			SecretError::Resolver(ref e) => Some(e.as_ref()),
+			SecretError::InvalidName(ref e) => Some(e),
+		}
+	}
+}
+
+impl std::fmt::Display for SecretError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			SecretError::Unknown(name) => write!(f, "Unknown secret: {}", name),
+			SecretError::Resolver(e) => write!(f, "Secret resolver error: {}", e),
+			SecretError::InvalidName(e) => write!(f, "Invalid secret header name: {}", e),
+		}
+	}
+}
+
+/// An error that can occur when authorizing a request with an auth provider.
+#[derive(Debug)]
+pub enum AuthError {
+	/// The request refers to an auth provider which isn't registered on the
+	/// client.
+	UnknownProvider(String),
+	/// The request to the token endpoint failed.
+	Http(reqwest::Error),
+	/// Fetching the token took too long.
+	Timeout,
+	/// The token endpoint responded with an error status.
+	Rejected(reqwest::StatusCode),
+	/// The response of the token endpoint couldn't be parsed.
+	Malformed(serde_json::Error),
+	/// The token can't be sent in a header.
+	InvalidToken(reqwest::header::InvalidHeaderValue),
+	/// A custom auth provider failed to fetch a token.
+	Provider(crate::auth::ProviderError),
+}
+
+impl std::error::Error for AuthError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match *self {
+			AuthError::UnknownProvider(_) | AuthError::Timeout | AuthError::Rejected(_) => None,
+			AuthError::Http(ref e) => Some(e),
+			AuthError::Malformed(ref e) => Some(e),
+			AuthError::InvalidToken(ref e) => Some(e),
+			AuthError::Provider(ref e) => Some(e.as_ref()),
+		}
+	}
+}
+
+impl std::fmt::Display for AuthError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			AuthError::UnknownProvider(name) => write!(f, "Unknown auth provider: {}", name),
+			AuthError::Http(e) => write!(f, "Token request error: {}", e),
+			AuthError::Timeout => write!(f, "Token request timed out"),
+			AuthError::Rejected(status) => write!(f, "Token endpoint responded with {}", status),
+			AuthError::Malformed(e) => write!(f, "Malformed token response: {}", e),
+			AuthError::InvalidToken(e) => write!(f, "Invalid token: {}", e),
+			AuthError::Provider(e) => write!(f, "Auth provider error: {}", e),
+		}
+	}
+}
+
+impl From<reqwest::Error> for AuthError {
+	fn from(e: reqwest::Error) -> Self {
+		AuthError::Http(e)
+	}
+}
+
+impl From<serde_json::Error> for AuthError {
+	fn from(e: serde_json::Error) -> Self {
+		AuthError::Malformed(e)
+	}
+}
+
+/// An error that can occur when sealing or opening an encrypted request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionError {
+	/// The request was sealed with a key whose ID the client doesn't know,
+	/// or the client has no encryption keys.
+	UnknownKey(u32),
+	/// The sealed request is too short to be valid.
+	Malformed,
+	/// The request couldn't be sealed, or failed to authenticate when being
+	/// opened.
+	Failed,
+}
+
+impl std::error::Error for EncryptionError {}
+
+impl std::fmt::Display for EncryptionError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			EncryptionError::UnknownKey(id) => write!(f, "Unknown encryption key: {}", id),
+			EncryptionError::Malformed => write!(f, "Encrypted request is malformed"),
+			EncryptionError::Failed => write!(f, "Encryption failed"),
+		}
+	}
+}
+
 /// An error that can occur when spawning a job.
 #[derive(Debug)]
 pub enum SpawnError {
@@ -38,6 +181,32 @@ pub enum SpawnError {
 	Receive(RecvError),
 	/// A request failed to (de)serialize
 	Serde(bincode::Error),
+	/// A request failed to be sealed or opened.
+	Encryption(EncryptionError),
+	/// The request ran out of attempts and was moved to the dead letter table.
+	DeadLetter,
+	/// The request expired before it could be delivered.
+	Expired,
+	/// The job was cancelled before a response was received.
+	Cancelled,
+	/// The job no longer exists, and left no response behind. This happens
+	/// when the job expired or got cleared from its channel.
+	Missing,
+	/// The idempotency key of the request was already claimed by the job with
+	/// the given UUID, which doesn't store its response.
+	NotReturning(uuid::Uuid),
+	/// The idempotency key of the request is forwarded to the server, but
+	/// isn't a valid header value.
+	InvalidIdempotencyKey(reqwest::header::InvalidHeaderValue),
+	/// The request refers to a signing key which isn't registered on the
+	/// client.
+	Signing(SigningError),
+	/// The request has secret headers which the client can't resolve, as it
+	/// has no resolver, or whose names aren't valid header names.
+	Secret(SecretError),
+	/// The request refers to an auth provider which isn't registered on the
+	/// client.
+	Auth(AuthError),
 }
 
 impl std::error::Error for SpawnError {
@@ -46,6 +215,16 @@ impl std::error::Error for SpawnError {
 			SpawnError::Sqlx(ref e) => Some(e),
 			SpawnError::Receive(ref e) => Some(e),
 			SpawnError::Serde(ref e) => Some(e),
+			SpawnError::Encryption(ref e) => Some(e),
+			SpawnError::InvalidIdempotencyKey(ref e) => Some(e),
+			SpawnError::Signing(ref e) => Some(e),
+			SpawnError::Secret(ref e) => Some(e),
+			SpawnError::Auth(ref e) => Some(e),
+			SpawnError::DeadLetter
+			| SpawnError::Expired
+			| SpawnError::Cancelled
+			| SpawnError::Missing
+			| SpawnError::NotReturning(_) => None,
 		}
 	}
 }
@@ -56,6 +235,22 @@ impl std::fmt::Display for SpawnError {
 			SpawnError::Receive(e) => write!(f, "Receiver error: {}", e),
 			SpawnError::Sqlx(e) => write!(f, "SQL error: {}", e),
 			SpawnError::Serde(e) => write!(f, "Serialization error: {}", e),
+			SpawnError::Encryption(e) => write!(f, "Encryption error: {}", e),
+			SpawnError::DeadLetter => write!(f, "Request ran out of attempts"),
+			SpawnError::Expired => write!(f, "Request expired before it could be delivered"),
+			SpawnError::Cancelled => write!(f, "Job was cancelled"),
+			SpawnError::Missing => write!(f, "Job no longer exists and left no response"),
+			SpawnError::NotReturning(id) => {
+				write!(
+					f,
+					"Idempotency key was claimed by job {} which doesn't return a response",
+					id
+				)
+			}
+			SpawnError::InvalidIdempotencyKey(e) => write!(f, "Invalid idempotency key: {}", e),
+			SpawnError::Signing(e) => write!(f, "Signing error: {}", e),
+			SpawnError::Secret(e) => write!(f, "Secret error: {}", e),
+			SpawnError::Auth(e) => write!(f, "Auth error: {}", e),
 		}
 	}
 }
@@ -78,6 +273,74 @@ impl From<bincode::Error> for SpawnError {
 	}
 }
 
+impl From<EncryptionError> for SpawnError {
+	fn from(e: EncryptionError) -> Self {
+		SpawnError::Encryption(e)
+	}
+}
+
+impl From<SigningError> for SpawnError {
+	fn from(e: SigningError) -> Self {
+		SpawnError::Signing(e)
+	}
+}
+
+impl From<SecretError> for SpawnError {
+	fn from(e: SecretError) -> Self {
+		SpawnError::Secret(e)
+	}
+}
+
+impl From<AuthError> for SpawnError {
+	fn from(e: AuthError) -> Self {
+		SpawnError::Auth(e)
+	}
+}
+
+/// An error that can occur when constructing a [`Client`](crate::Client).
+#[derive(Debug)]
+pub enum ClientError {
+	/// The sql query was not successfully executed
+	Sqlx(sqlx::Error),
+	/// The HTTP client could not be constructed
+	Http(reqwest::Error),
+	/// The rate limit of the given channel or host allows no requests, or
+	/// has an empty period.
+	InvalidRateLimit(String),
+}
+
+impl std::error::Error for ClientError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match *self {
+			ClientError::Sqlx(ref e) => Some(e),
+			ClientError::Http(ref e) => Some(e),
+			ClientError::InvalidRateLimit(_) => None,
+		}
+	}
+}
+
+impl std::fmt::Display for ClientError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			ClientError::Sqlx(e) => write!(f, "SQL error: {}", e),
+			ClientError::Http(e) => write!(f, "HTTP client error: {}", e),
+			ClientError::InvalidRateLimit(key) => write!(f, "Invalid rate limit of {}", key),
+		}
+	}
+}
+
+impl From<sqlx::Error> for ClientError {
+	fn from(e: sqlx::Error) -> Self {
+		ClientError::Sqlx(e)
+	}
+}
+
+impl From<reqwest::Error> for ClientError {
+	fn from(e: reqwest::Error) -> Self {
+		ClientError::Http(e)
+	}
+}
+
 /// Errors which happen when converting requests from the [`http`] crate.
 #[cfg(feature = "http")]
 #[derive(Debug)]
@@ -0,0 +1,262 @@
+//! The attempt history records every attempt to deliver a request, unlike the
+//! outcome reported by [`Client::status`](crate::Client::status), which only
+//! covers the latest one. It's opt-in, as its table grows with each attempt:
+//! its migrations are run with
+//! [`migrate_attempt_history`](crate::migrate_attempt_history), and the job
+//! runner records attempts once the client is built with
+//! [`ClientBuilder::attempt_history`](crate::client::ClientBuilder::attempt_history).
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use sqlx::{postgres::PgRow, Executor, PgPool, Postgres, Row};
+use uuid::Uuid;
+
+use crate::status::Outcome;
+
+/// How often the attempts which are past their retention are removed.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// The settings of the attempt history.
+///
+/// # Example
+/// ```
+/// use std::time::Duration;
+///
+/// use requeuest::history::AttemptHistory;
+///
+/// // Keep attempts for a week, along with the first kilobyte of responses
+/// let week = Duration::from_secs(7 * 24 * 60 * 60);
+/// let history = AttemptHistory::new(week).max_body_size(1024);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[must_use]
+pub struct AttemptHistory {
+	/// How long attempts are kept after they were started. Older attempts are
+	/// removed by the client every few minutes.
+	pub retention: Duration,
+	/// How many bytes of the body of each response are recorded. Defaults to
+	/// 0, which doesn't record bodies at all.
+	pub max_body_size: usize,
+}
+
+impl AttemptHistory {
+	/// Constructs attempt history settings which keep attempts for the given
+	/// amount of time, without their response bodies.
+	pub fn new(retention: Duration) -> Self {
+		Self { retention, max_body_size: 0 }
+	}
+
+	/// Sets how many bytes of the body of each response are recorded.
+	pub fn max_body_size(mut self, max_body_size: usize) -> Self {
+		self.max_body_size = max_body_size;
+		self
+	}
+}
+
+/// The kind of error an attempt failed with, if no response was received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+	/// The attempt timed out.
+	Timeout,
+	/// Connecting to the server failed.
+	Connect,
+	/// Following a redirect failed.
+	Redirect,
+	/// Reading the body of the response failed.
+	Body,
+	/// The request couldn't be built or sent.
+	Request,
+	/// The request can never be sent as it is, e.g. because its idempotency
+	/// key isn't a valid header value, it refers to a signing key or auth
+	/// provider which isn't registered or a secret which can't be resolved, or
+	/// it can't be opened with the client's encryption keys, so it was moved
+	/// to the dead letters right away instead of being retried.
+	Invalid,
+	/// Any other error.
+	Other,
+}
+
+impl ErrorKind {
+	/// Classifies the given error of sending a request.
+	pub(crate) fn of(error: &reqwest::Error) -> Self {
+		if error.is_timeout() {
+			ErrorKind::Timeout
+		} else if error.is_connect() {
+			ErrorKind::Connect
+		} else if error.is_redirect() {
+			ErrorKind::Redirect
+		} else if error.is_body() || error.is_decode() {
+			ErrorKind::Body
+		} else if error.is_request() || error.is_builder() {
+			ErrorKind::Request
+		} else {
+			ErrorKind::Other
+		}
+	}
+
+	/// Returns the name the kind is stored as.
+	fn as_str(self) -> &'static str {
+		match self {
+			ErrorKind::Timeout => "timeout",
+			ErrorKind::Connect => "connect",
+			ErrorKind::Redirect => "redirect",
+			ErrorKind::Body => "body",
+			ErrorKind::Request => "request",
+			ErrorKind::Invalid => "invalid",
+			ErrorKind::Other => "other",
+		}
+	}
+
+	/// Parses the name the kind is stored as.
+	fn parse(kind: &str) -> Self {
+		match kind {
+			"timeout" => ErrorKind::Timeout,
+			"connect" => ErrorKind::Connect,
+			"redirect" => ErrorKind::Redirect,
+			"body" => ErrorKind::Body,
+			"request" => ErrorKind::Request,
+			"invalid" => ErrorKind::Invalid,
+			_ => ErrorKind::Other,
+		}
+	}
+}
+
+/// A recorded attempt to deliver a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttemptRecord {
+	/// The UUID of the job which made the attempt.
+	pub id: Uuid,
+	/// The number of the attempt, counting from 1.
+	pub number: u32,
+	/// When the attempt was started.
+	pub started_at: DateTime<Utc>,
+	/// How long the attempt took.
+	pub duration: Duration,
+	/// The outcome of the attempt.
+	pub outcome: Outcome,
+	/// The kind of error the attempt failed with, if no response was received.
+	pub error_kind: Option<ErrorKind>,
+	/// The start of the body of the response, if one was received and bodies
+	/// are recorded.
+	pub body: Option<Vec<u8>>,
+}
+
+impl AttemptRecord {
+	/// Constructs an attempt from a row of the attempt table.
+	fn from_row(row: &PgRow) -> Result<Self, sqlx::Error> {
+		let number: i32 = row.try_get("attempt")?;
+		let duration: i64 = row.try_get("duration_ms")?;
+		let error_kind: Option<&str> = row.try_get("error_kind")?;
+		let outcome = Outcome::from_columns(row.try_get("status")?, row.try_get("error")?);
+		Ok(Self {
+			id: row.try_get("id")?,
+			number: u32::try_from(number).unwrap_or_default(),
+			started_at: row.try_get("started_at")?,
+			duration: Duration::from_millis(u64::try_from(duration).unwrap_or_default()),
+			outcome: outcome.unwrap_or_else(|| Outcome::Error(String::new())),
+			error_kind: error_kind.map(ErrorKind::parse),
+			body: row.try_get("body")?,
+		})
+	}
+}
+
+/// Records the attempts of a job runner, if the attempt history is enabled.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Recorder(Option<AttemptHistory>);
+
+impl Recorder {
+	/// Constructs a recorder with the given settings, which doesn't record
+	/// anything if there are none.
+	pub fn new(history: Option<AttemptHistory>) -> Self {
+		Self(history)
+	}
+
+	/// Reads the start of the body of the given response, if bodies are
+	/// recorded. Errors while reading it end the body early.
+	pub async fn body(&self, mut response: reqwest::Response) -> Option<Vec<u8>> {
+		let max = self.max_body_size()?;
+		let mut body = Vec::new();
+		while body.len() < max {
+			let Ok(Some(chunk)) = response.chunk().await else {
+				break;
+			};
+			body.extend_from_slice(&chunk[..chunk.len().min(max - body.len())]);
+		}
+		Some(body)
+	}
+
+	/// Returns the start of the given response body, if bodies are recorded.
+	pub fn truncate(&self, body: &[u8]) -> Option<Vec<u8>> {
+		let max = self.max_body_size()?;
+		Some(body[..body.len().min(max)].to_vec())
+	}
+
+	/// Returns how many bytes of response bodies are recorded, if any are.
+	pub fn max_body_size(&self) -> Option<usize> {
+		self.0.map(|history| history.max_body_size).filter(|max| *max > 0)
+	}
+
+	/// Records the given attempt.
+	pub async fn record<'e, E>(
+		&self,
+		executor: E,
+		attempt: &AttemptRecord,
+	) -> Result<(), sqlx::Error>
+	where
+		E: Executor<'e, Database = Postgres>,
+	{
+		if self.0.is_none() {
+			return Ok(());
+		}
+		let (status, error) = attempt.outcome.columns();
+		sqlx::query(
+			"INSERT INTO requeuest_attempts
+				(id, attempt, started_at, duration_ms, status, error_kind, error, body)
+			VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+		)
+		.bind(attempt.id)
+		.bind(i32::try_from(attempt.number).unwrap_or(i32::MAX))
+		.bind(attempt.started_at)
+		.bind(i64::try_from(attempt.duration.as_millis()).unwrap_or(i64::MAX))
+		.bind(status)
+		.bind(attempt.error_kind.map(ErrorKind::as_str))
+		.bind(error)
+		.bind(attempt.body.as_deref())
+		.execute(executor)
+		.await?;
+		Ok(())
+	}
+}
+
+/// Removes the attempts which are past the retention of the given attempt
+/// history periodically, until the task is aborted.
+pub(crate) async fn expire(pool: PgPool, history: AttemptHistory) {
+	let retention = i64::try_from(history.retention.as_millis()).unwrap_or(i64::MAX);
+	let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+	loop {
+		interval.tick().await;
+		// Attempts which failed to be removed are removed at the next interval
+		sqlx::query(
+			"DELETE FROM requeuest_attempts WHERE started_at < NOW() - $1 * INTERVAL '1 millisecond'",
+		)
+		.bind(retention)
+		.execute(&pool)
+		.await
+		.ok();
+	}
+}
+
+/// Fetches the recorded attempts of the job with the given UUID, in the order
+/// they were made.
+pub(crate) async fn fetch(pool: &PgPool, id: Uuid) -> Result<Vec<AttemptRecord>, sqlx::Error> {
+	sqlx::query(
+		"SELECT * FROM requeuest_attempts WHERE id = $1 ORDER BY attempt ASC, started_at ASC",
+	)
+	.bind(id)
+	.fetch_all(pool)
+	.await?
+	.iter()
+	.map(AttemptRecord::from_row)
+	.collect()
+}
@@ -0,0 +1,141 @@
+//! Hooks let the application react to what happens to spawned requests, e.g.
+//! to mark an invoice as delivered once its webhook succeeds, without waiting
+//! for the response with
+//! [`Client::spawn_returning`](crate::Client::spawn_returning). They're called
+//! by the job runner of the client they're registered on with
+//! [`ClientBuilder::hooks`](crate::client::ClientBuilder::hooks), after each
+//! attempt to deliver a request.
+
+use std::{fmt, sync::Arc};
+
+use reqwest::{header::HeaderMap, StatusCode};
+use sqlxmq::CurrentJob;
+use uuid::Uuid;
+
+use crate::{request::Request, status::Outcome};
+
+/// The response a request was delivered with.
+#[derive(Debug, Clone, Copy)]
+pub struct Delivered<'a> {
+	/// The accepted status code of the response.
+	pub status: StatusCode,
+	/// The headers of the response.
+	pub headers: &'a HeaderMap,
+	/// The body of the response, if the job read it. Only jobs which store
+	/// their response, e.g. ones spawned with
+	/// [`Client::spawn_returning`](crate::Client::spawn_returning), read the
+	/// whole body.
+	pub body: Option<&'a [u8]>,
+}
+
+/// Callbacks for the result of attempts to deliver requests. All methods do
+/// nothing by default.
+///
+/// The hooks are called from the task running the job, so they should return
+/// quickly, and move work which takes a while or has to be awaited to a task of
+/// its own.
+///
+/// Hooks are called after the result of an attempt was committed, so they're
+/// called at most once for it: if the process stops in between, the hook isn't
+/// called, and the attempt isn't made again. Use a
+/// [`Callback`](crate::callback::Callback), which is queued in the same
+/// transaction, or look the job up with
+/// [`Client::status`](crate::Client::status) where a notification mustn't be
+/// lost.
+pub trait Hooks: Send + Sync + 'static {
+	/// Called once the request of the job with the given UUID, which was
+	/// spawned on the given channel, was delivered with the given response.
+	#[allow(unused_variables)]
+	fn on_success(&self, id: Uuid, channel: &str, request: &Request, response: Delivered<'_>) {}
+
+	/// Called when an attempt to deliver the request of the job with the given
+	/// UUID, which was spawned on the given channel, failed with the given
+	/// outcome, and the request will be retried.
+	#[allow(unused_variables)]
+	fn on_retry(&self, id: Uuid, channel: &str, request: &Request, outcome: &Outcome) {}
+
+	/// Called when the request of the job with the given UUID, which was
+	/// spawned on the given channel, is given up on. The outcome is the one of
+	/// the last attempt if the request ran out of attempts and was moved to
+	/// the dead letter table, or `None` if it expired.
+	#[allow(unused_variables)]
+	fn on_giving_up(&self, id: Uuid, channel: &str, request: &Request, outcome: Option<&Outcome>) {}
+
+	/// Called when an attempt to deliver the request of the job with the given
+	/// UUID, which was spawned on the given channel, failed with the given
+	/// outcome after the job was cancelled with
+	/// [`Client::cancel`](crate::Client::cancel), so it isn't retried.
+	#[allow(unused_variables)]
+	fn on_cancelled(&self, id: Uuid, channel: &str, request: &Request, outcome: &Outcome) {}
+}
+
+/// Calls the hooks registered on a client, if there are any.
+#[derive(Clone, Default)]
+pub(crate) struct Notifier(Option<Arc<dyn Hooks>>);
+
+impl fmt::Debug for Notifier {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_tuple("Notifier").field(&self.0.is_some()).finish()
+	}
+}
+
+impl Notifier {
+	/// Constructs a notifier which calls the given hooks.
+	pub fn new(hooks: impl Hooks) -> Self {
+		Self(Some(Arc::new(hooks)))
+	}
+
+	/// Notifies the hooks that the request of the given job was delivered.
+	pub fn success(&self, id: Uuid, channel: &str, request: &Request, response: Delivered<'_>) {
+		if let Some(hooks) = &self.0 {
+			hooks.on_success(id, channel, request, response);
+		}
+	}
+
+	/// Notifies the hooks that an attempt to deliver the request of the given
+	/// job failed, where `buried` is whether it was given up on and moved to
+	/// the dead letter table.
+	pub fn failure(
+		&self,
+		id: Uuid,
+		channel: &str,
+		request: &Request,
+		outcome: &Outcome,
+		buried: bool,
+	) {
+		match &self.0 {
+			Some(hooks) if buried => hooks.on_giving_up(id, channel, request, Some(outcome)),
+			Some(hooks) => hooks.on_retry(id, channel, request, outcome),
+			None => {}
+		}
+	}
+
+	/// Notifies the hooks that an attempt to deliver the request of the given
+	/// job failed after the job was cancelled.
+	pub fn cancelled(&self, id: Uuid, channel: &str, request: &Request, outcome: &Outcome) {
+		if let Some(hooks) = &self.0 {
+			hooks.on_cancelled(id, channel, request, outcome);
+		}
+	}
+
+	/// Looks up the channel of the given job, which the hooks are called with
+	/// if its request expires, since they aren't called after an attempt.
+	/// Returns `None` if there are no hooks to call.
+	pub async fn channel(&self, job: &CurrentJob) -> Result<Option<String>, sqlx::Error> {
+		if self.0.is_none() {
+			return Ok(None);
+		}
+		sqlx::query_scalar("SELECT channel_name FROM mq_msgs WHERE id = $1")
+			.bind(job.id())
+			.fetch_optional(job.pool())
+			.await
+	}
+
+	/// Notifies the hooks that the request of the given job, spawned on the
+	/// given channel, expired before it was delivered.
+	pub fn expired(&self, id: Uuid, channel: Option<&str>, request: &Request) {
+		if let Some(hooks) = &self.0 {
+			hooks.on_giving_up(id, channel.unwrap_or_default(), request, None);
+		}
+	}
+}
@@ -0,0 +1,78 @@
+//! Idempotency keys deduplicate spawned requests. The first job spawned with a
+//! key claims it, and spawning another request with the same key within the
+//! idempotency window returns the UUID of that job instead of queueing the
+//! request again. Once the window has passed, the key can be claimed anew.
+
+use std::{collections::HashMap, time::Duration};
+
+use reqwest::header::HeaderName;
+use sqlx::{PgConnection, PgPool, Row};
+use uuid::Uuid;
+
+/// The header idempotency keys are forwarded to the server in.
+pub(crate) const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+
+/// Claims the given idempotency keys for the jobs with the UUIDs at the same
+/// positions, which are returning jobs if `returning` is set. Keys which were
+/// already claimed within the given window are left alone, and returned along
+/// with the UUIDs of the jobs which claimed them. The keys have to be
+/// distinct.
+pub(crate) async fn claim(
+	conn: &mut PgConnection,
+	keys: &[&str],
+	ids: &[Uuid],
+	returning: bool,
+	window: Duration,
+) -> Result<HashMap<String, Uuid>, sqlx::Error> {
+	if keys.is_empty() {
+		return Ok(HashMap::new());
+	}
+	let window = i64::try_from(window.as_millis()).unwrap_or(i64::MAX);
+	let claimed: Vec<String> = sqlx::query_scalar(
+		"INSERT INTO requeuest_idempotency_keys AS claims (key, id, returning)
+		SELECT *, $3 FROM UNNEST($1::TEXT[], $2::UUID[])
+		ON CONFLICT (key) DO UPDATE
+		SET id = EXCLUDED.id, returning = EXCLUDED.returning, created_at = NOW()
+		WHERE claims.created_at <= NOW() - $4 * INTERVAL '1 millisecond'
+		RETURNING key",
+	)
+	.bind(keys)
+	.bind(ids)
+	.bind(returning)
+	.bind(window)
+	.fetch_all(&mut *conn)
+	.await?;
+	if claimed.len() == keys.len() {
+		return Ok(HashMap::new());
+	}
+
+	sqlx::query(
+		"SELECT key, id FROM requeuest_idempotency_keys WHERE key = ANY($1) AND NOT key = ANY($2)",
+	)
+	.bind(keys)
+	.bind(&claimed)
+	.fetch_all(&mut *conn)
+	.await?
+	.iter()
+	.map(|row| Ok((row.try_get("key")?, row.try_get("id")?)))
+	.collect()
+}
+
+/// Returns whether the job with the given UUID claimed an idempotency key as a
+/// returning job. Returns `None` if it holds no claim.
+pub(crate) async fn is_returning(pool: &PgPool, id: Uuid) -> Result<Option<bool>, sqlx::Error> {
+	sqlx::query_scalar("SELECT returning FROM requeuest_idempotency_keys WHERE id = $1")
+		.bind(id)
+		.fetch_optional(pool)
+		.await
+}
+
+/// Releases the idempotency key claimed by the job with the given UUID, if
+/// any, so another job can claim it.
+pub(crate) async fn release(conn: &mut PgConnection, id: Uuid) -> Result<(), sqlx::Error> {
+	sqlx::query("DELETE FROM requeuest_idempotency_keys WHERE id = $1")
+		.bind(id)
+		.execute(conn)
+		.await?;
+	Ok(())
+}
@@ -2,22 +2,50 @@
 
 use std::{
 	collections::HashMap,
-	sync::{Arc, LockResult, Mutex, MutexGuard},
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
 };
 
+use chrono::{DateTime, Utc};
+use reqwest::{header::HeaderValue, StatusCode};
+use sqlx::{Executor, Postgres};
 use sqlxmq::{job, CurrentJob};
 use tokio::sync::oneshot;
 use uuid::Uuid;
 
-use crate::{error::JobError, request::Request};
+#[cfg(feature = "metrics")]
+use crate::metric;
+#[cfg(feature = "tracing")]
+use crate::trace;
+use crate::{
+	auth::Authenticator,
+	callback::{FinalState, Followup, Received, Report},
+	circuit::{Breaker, Permit},
+	dead_letter,
+	encryption::Cipher,
+	error::{AuthError, JobError, SecretError, SigningError, SpawnError},
+	history::{AttemptRecord, ErrorKind, Recorder},
+	hooks::{Delivered, Notifier},
+	idempotency::IDEMPOTENCY_KEY,
+	rate_limit::RateLimiter,
+	request::Request,
+	response::{self, Response},
+	retry::{self, RetryAfterLimit},
+	secret::Secrets,
+	signing::Signer,
+	status::{self, Attempt, Outcome},
+	trace_context::TraceContext,
+	transport::Transport,
+};
 
 /// Alias for the result type sqlxmq jobs expect.
 pub type JobResult = Result<(), Box<dyn std::error::Error + Send + Sync + 'static>>;
 
 /// Alias for a map from request UUID to associated oneshot sender
-type SenderMap = HashMap<Uuid, oneshot::Sender<reqwest::Response>>;
+type SenderMap = HashMap<Uuid, oneshot::Sender<Result<Response, SpawnError>>>;
 
-/// Mechanism for returning responses from successful jobs.
+/// Mechanism for returning responses from finished jobs to the tasks waiting
+/// for them.
 #[derive(Debug)]
 pub(crate) struct ResponseSender(Arc<Mutex<SenderMap>>);
 
@@ -27,9 +55,44 @@ impl ResponseSender {
 		ResponseSender(Arc::new(Mutex::new(HashMap::new())))
 	}
 
-	/// Gets a lock to the mutex wrapping the map.
-	pub fn lock(&self) -> LockResult<MutexGuard<'_, SenderMap>> {
-		self.0.lock()
+	/// Registers a task as waiting for the result of the job with the given
+	/// UUID. A task which was already waiting for the same job stops receiving
+	/// its result.
+	pub fn register(&self, id: Uuid) -> oneshot::Receiver<Result<Response, SpawnError>> {
+		let (sender, receiver) = oneshot::channel();
+		#[allow(clippy::unwrap_used)] // We don't handle poisoning
+		self.0.lock().unwrap().insert(id, sender);
+		receiver
+	}
+
+	/// Removes the waiting task for the job with the given UUID.
+	pub fn remove(&self, id: Uuid) {
+		#[allow(clippy::unwrap_used)] // We don't handle poisoning
+		self.0.lock().unwrap().remove(&id);
+	}
+
+	/// Returns true if a task is waiting for the job with the given UUID.
+	pub fn is_waiting(&self, id: Uuid) -> bool {
+		#[allow(clippy::unwrap_used)] // We don't handle poisoning
+		self.0.lock().unwrap().contains_key(&id)
+	}
+
+	/// Returns the UUIDs of all jobs which tasks are waiting for.
+	pub fn waiting(&self) -> Vec<Uuid> {
+		#[allow(clippy::unwrap_used)] // We don't handle poisoning
+		self.0.lock().unwrap().keys().copied().collect()
+	}
+
+	/// Sends the result of a returning job to the task waiting for it, if
+	/// there is one in this process.
+	pub fn send(&self, id: Uuid, result: Result<Response, SpawnError>) {
+		#[allow(clippy::unwrap_used)] // We don't handle poisoning
+		let sender = self.0.lock().unwrap().remove(&id);
+		if let Some(sender) = sender {
+			// The receiving end having been dropped just means no one is
+			// waiting anymore.
+			sender.send(result).ok();
+		}
 	}
 }
 
@@ -39,54 +102,751 @@ impl Clone for ResponseSender {
 	}
 }
 
-/// The function which runs HTTP jobs and actually sends the requests.
-#[job(name = "http")]
-pub async fn http(mut job: CurrentJob, client: reqwest::Client) -> JobResult {
-	// validate the job payload
-	let payload = job.raw_bytes().ok_or(JobError::MissingRequest)?;
-	let request: Request = bincode::deserialize(payload)?;
+/// The parts of the job runner which add credentials to requests at each
+/// attempt.
+#[derive(Debug, Clone)]
+pub(crate) struct Credentials {
+	/// Resolves the secret headers of requests.
+	pub secrets: Secrets,
+	/// Fetches the bearer tokens of requests.
+	pub auth: Authenticator,
+	/// Signs requests.
+	pub signer: Signer,
+}
 
-	// construct and send the request
-	let mut builder = client.request(request.method, request.url).headers(request.headers);
-	if let Some(body) = request.body {
-		builder = builder.body(body);
+impl Credentials {
+	/// Checks that the credentials the given request refers to are
+	/// registered, as it can never be sent otherwise.
+	pub fn check(&self, request: &Request) -> Result<(), SpawnError> {
+		self.secrets.check(request)?;
+		self.auth.check(request)?;
+		self.signer.check(request)?;
+		Ok(())
 	}
-	let response = builder.send().await?;
+}
 
-	// complete the job if the response is in the accepted set
-	if request.accept_responses.iter().any(|accepted| accepted.accepts(response.status())) {
-		job.complete().await?;
+/// The function which runs HTTP jobs and actually sends the requests.
+#[allow(clippy::too_many_arguments)] // sqlxmq passes each context as an argument
+#[job(name = "http")]
+pub async fn http(
+	mut job: CurrentJob,
+	transport: Transport,
+	limiter: RateLimiter,
+	breaker: Breaker,
+	recorder: Recorder,
+	retry_limit: RetryAfterLimit,
+	hooks: Notifier,
+	credentials: Credentials,
+	cipher: Cipher,
+) -> JobResult {
+	let result = deliver(
+		&mut job,
+		&transport,
+		&limiter,
+		&breaker,
+		&recorder,
+		retry_limit,
+		&hooks,
+		&credentials,
+		&cipher,
+	)
+	.await;
+	match result {
+		Ok(outcome) => outcome.map_or(Ok(()), failure),
+		Err(error) => {
+			bury_on_error(&mut job, error.as_ref()).await;
+			Err(error)
+		}
 	}
+}
 
-	Ok(())
+/// Runs an HTTP job. Returns the outcome of the attempt it made if it failed,
+/// once it was handled.
+#[allow(clippy::too_many_arguments)]
+async fn deliver(
+	job: &mut CurrentJob,
+	transport: &Transport,
+	limiter: &RateLimiter,
+	breaker: &Breaker,
+	recorder: &Recorder,
+	retry_limit: RetryAfterLimit,
+	hooks: &Notifier,
+	credentials: &Credentials,
+	cipher: &Cipher,
+) -> Result<Option<Outcome>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+	let start = Delivery::start(job, limiter, breaker, recorder, hooks, cipher).await?;
+	let mut delivery = match start {
+		Start::Ready(delivery) => delivery,
+		Start::Expired | Start::Postponed | Start::Cancelled | Start::Unreadable => {
+			return Ok(None)
+		}
+	};
+
+	let (outcome, retry_after) =
+		match delivery.send(job, transport, breaker, recorder, retry_limit, credentials).await {
+			Sent::Accepted(response) => {
+				let status = response.status();
+				let headers = response.headers().clone();
+				let outcome = Outcome::Response(status);
+				delivery.read(recorder, response, true).await;
+				let mut tx = job.pool().begin().await?;
+				status::record(&mut *tx, job.id(), &outcome, true).await?;
+				delivery.record(recorder, &mut *tx, job.id(), &outcome).await?;
+				if let Some(followup) =
+					delivery.report(job.id(), FinalState::Delivered, &outcome, cipher)?
+				{
+					followup.spawn(&mut *tx).await?;
+				}
+				job.complete_with_transaction(tx).await?;
+				let response = Delivered { status, headers: &headers, body: None };
+				hooks.success(job.id(), &delivery.attempt.channel, &delivery.request, response);
+				return Ok(None);
+			}
+			Sent::Failed(outcome, retry_after) => (outcome, retry_after),
+		};
+
+	delivery.fail(job, recorder, hooks, cipher, &outcome, retry_after).await?;
+	Ok(Some(outcome))
 }
 
-/// Sends the response to the HTTP request back via a oneshot channel.
+/// Stores the response to the HTTP request, and sends it to the task waiting
+/// for it.
+#[allow(clippy::too_many_arguments)] // sqlxmq passes each context as an argument
 #[job(name = "http_response")]
 pub async fn http_response(
 	mut job: CurrentJob,
-	client: reqwest::Client,
+	transport: Transport,
+	limiter: RateLimiter,
+	breaker: Breaker,
+	recorder: Recorder,
+	retry_limit: RetryAfterLimit,
+	hooks: Notifier,
+	credentials: Credentials,
+	cipher: Cipher,
 	sender: ResponseSender,
 ) -> JobResult {
-	// validate the job payload
-	let payload = job.raw_bytes().ok_or(JobError::MissingRequest)?;
-	let request: Request = bincode::deserialize(payload)?;
+	let result = deliver_response(
+		&mut job,
+		&transport,
+		&limiter,
+		&breaker,
+		&recorder,
+		retry_limit,
+		&hooks,
+		&credentials,
+		&cipher,
+		&sender,
+	)
+	.await;
+	match result {
+		Ok(outcome) => outcome.map_or(Ok(()), failure),
+		Err(error) => {
+			if bury_on_error(&mut job, error.as_ref()).await {
+				sender.send(job.id(), Err(SpawnError::DeadLetter));
+			}
+			Err(error)
+		}
+	}
+}
+
+/// Runs an HTTP job which stores its response, and sends it to the task
+/// waiting for it. Returns the outcome of the attempt it made if it failed,
+/// once it was handled.
+#[allow(clippy::too_many_arguments)]
+async fn deliver_response(
+	job: &mut CurrentJob,
+	transport: &Transport,
+	limiter: &RateLimiter,
+	breaker: &Breaker,
+	recorder: &Recorder,
+	retry_limit: RetryAfterLimit,
+	hooks: &Notifier,
+	credentials: &Credentials,
+	cipher: &Cipher,
+	sender: &ResponseSender,
+) -> Result<Option<Outcome>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+	let start = Delivery::start(job, limiter, breaker, recorder, hooks, cipher).await?;
+	let mut delivery = match start {
+		Start::Ready(delivery) => delivery,
+		// The task waiting for the response was told by the cancellation
+		Start::Postponed | Start::Cancelled => return Ok(None),
+		Start::Unreadable => {
+			sender.send(job.id(), Err(SpawnError::DeadLetter));
+			return Ok(None);
+		}
+		Start::Expired => {
+			// The waiting task might have stopped waiting already, in which case
+			// there's no one to notify.
+			response::notify(job.pool(), job.id()).await?;
+			sender.send(job.id(), Err(SpawnError::Expired));
+			return Ok(None);
+		}
+	};
 
-	// construct and send the request
-	let mut builder = client.request(request.method, request.url);
-	if let Some(body) = request.body {
-		builder = builder.body(body);
+	let (outcome, retry_after) =
+		match delivery.send(job, transport, breaker, recorder, retry_limit, credentials).await {
+			Sent::Accepted(response) => {
+				let status = response.status();
+				let outcome = Outcome::Response(status);
+				match Response::read(job.id(), response).await {
+					Ok(response) => {
+						delivery.body = recorder.truncate(&response.body);
+						delivery.received = delivery.request.callback.as_ref().map(|callback| {
+							callback.received(&response.headers, Some(&response.body))
+						});
+						// store the response in the same transaction the job is completed
+						// in, so it can be collected even if the waiting task is gone
+						let mut tx = job.pool().begin().await?;
+						response.store(&mut tx).await?;
+						status::record(&mut *tx, job.id(), &outcome, true).await?;
+						delivery.record(recorder, &mut *tx, job.id(), &outcome).await?;
+						if let Some(followup) =
+							delivery.report(job.id(), FinalState::Delivered, &outcome, cipher)?
+						{
+							followup.spawn(&mut *tx).await?;
+						}
+						job.complete_with_transaction(tx).await?;
+						let delivered = Delivered {
+							status,
+							headers: &response.headers,
+							body: Some(&response.body),
+						};
+						hooks.success(
+							job.id(),
+							&delivery.attempt.channel,
+							&delivery.request,
+							delivered,
+						);
+						sender.send(job.id(), Ok(response));
+						return Ok(None);
+					}
+					Err(error) => {
+						delivery.error_kind = Some(ErrorKind::of(&error));
+						(Outcome::Error(error.to_string()), None)
+					}
+				}
+			}
+			Sent::Failed(outcome, retry_after) => (outcome, retry_after),
+		};
+
+	if delivery.fail(job, recorder, hooks, cipher, &outcome, retry_after).await? {
+		sender.send(job.id(), Err(SpawnError::DeadLetter));
 	}
-	let response = builder.send().await?;
+	Ok(Some(outcome))
+}
 
-	// complete the job if the response is in the accepted set
-	if request.accept_responses.iter().any(|accepted| accepted.accepts(response.status())) {
-		job.complete().await?;
+/// How a job proceeds after being picked up.
+enum Start {
+	/// The request has expired, and the job was completed without making an
+	/// attempt.
+	Expired,
+	/// The job was postponed because of a rate limit or an open circuit.
+	Postponed,
+	/// The job was cancelled after it was picked up, so no attempt was made.
+	Cancelled,
+	/// The request couldn't be opened, and the job was moved to the dead
+	/// letter table.
+	Unreadable,
+	/// An attempt to deliver the request has started.
+	Ready(Box<Delivery>),
+}
 
-		#[allow(clippy::unwrap_used)] // We don't handle poisoning
-		let sender = sender.0.lock().unwrap().remove(&job.id()).ok_or(JobError::MissingSender)?;
-		sender.send(response).or(Err(JobError::MissingReceiver))?;
+/// The result of sending a request.
+enum Sent {
+	/// A response with an accepted status code was received.
+	Accepted(reqwest::Response),
+	/// The attempt failed with the given outcome. The server may have asked to
+	/// be retried after the given amount of time.
+	Failed(Outcome, Option<Duration>),
+}
+
+/// An attempt to deliver the request of a job, shared by all kinds of jobs.
+struct Delivery {
+	/// The request being delivered.
+	request: Request,
+	/// The attempt being made.
+	attempt: Attempt,
+	/// When the attempt was started.
+	started_at: DateTime<Utc>,
+	/// The instant the attempt was started at, to measure how long it took.
+	started: Instant,
+	/// The kind of error the attempt failed with, if no response was received.
+	error_kind: Option<ErrorKind>,
+	/// The start of the body of the response, if it's recorded in the attempt
+	/// history.
+	body: Option<Vec<u8>>,
+	/// The parts of the response which are reported to the callback of the
+	/// request, if it has one.
+	received: Option<Received>,
+	/// The trace context sent to the server, if it's propagated.
+	trace_context: Option<TraceContext>,
+	/// The span of the attempt.
+	#[cfg(feature = "tracing")]
+	span: tracing::Span,
+}
+
+impl Delivery {
+	/// Reads the request of the given job, opening it with the given cipher,
+	/// and starts an attempt to deliver it unless it can't be opened, has
+	/// expired, has to wait for the rate limits it falls under or
+	/// for the circuit of its host to close, or the job was cancelled.
+	async fn start(
+		job: &mut CurrentJob,
+		limiter: &RateLimiter,
+		breaker: &Breaker,
+		recorder: &Recorder,
+		hooks: &Notifier,
+		cipher: &Cipher,
+	) -> Result<Start, Box<dyn std::error::Error + Send + Sync + 'static>> {
+		// validate the job payload
+		let payload = job.raw_bytes().ok_or(JobError::MissingRequest)?;
+		let request = match cipher.decode(payload) {
+			Ok(request) => request,
+			Err(error) => return Ok(bury_unreadable(job, recorder, &error).await?),
+		};
+
+		// give up on the request once it has expired
+		if request.is_expired() {
+			let channel = hooks.channel(job).await?;
+			match &request.callback {
+				Some(callback) => {
+					let followup = Report::expired(job).await?.request(callback, cipher)?;
+					let mut tx = job.pool().begin().await?;
+					followup.spawn(&mut *tx).await?;
+					job.complete_with_transaction(tx).await?;
+				}
+				None => job.complete().await?,
+			}
+			hooks.expired(job.id(), channel.as_deref(), &request);
+			#[cfg(feature = "tracing")]
+			trace::expired(job);
+			#[cfg(feature = "metrics")]
+			metric::expired(&request);
+			return Ok(Start::Expired);
+		}
+
+		// wait for the circuit of its host to close, and for the rate limits the
+		// request falls under. The circuit is checked first, so requests held
+		// back by it don't use up rate limit tokens.
+		if let Permit::Wait(wait) = breaker.permit(job.pool(), &request).await? {
+			postpone(job, wait).await?;
+			return Ok(Start::Postponed);
+		}
+		if let Some(wait) = limiter.acquire(job, &request).await? {
+			postpone(job, wait).await?;
+			return Ok(Start::Postponed);
+		}
+
+		let Some(attempt) = status::attempt_started(job).await? else {
+			return Ok(Start::Cancelled);
+		};
+		let (trace_context, propagate) = TraceContext::of_job(job).unzip();
+		#[cfg(feature = "tracing")]
+		let span = trace::attempt_span(job, &request, &attempt, trace_context.as_ref());
+		Ok(Start::Ready(Box::new(Delivery {
+			request,
+			attempt,
+			started_at: Utc::now(),
+			started: Instant::now(),
+			error_kind: None,
+			body: None,
+			received: None,
+			trace_context: trace_context.filter(|_| propagate == Some(true)),
+			#[cfg(feature = "tracing")]
+			span,
+		})))
+	}
+
+	/// Constructs and sends the request, applying its headers, body and
+	/// timeouts, forwarding its idempotency key and trace context if asked to,
+	/// resolving its secret headers, authorizing it if it refers to an auth
+	/// provider and signing it if it refers to a signing key, and checks
+	/// whether the response has an accepted status code. A request whose token
+	/// is rejected is sent again once with a new one. Reads the body of a
+	/// rejected response as far as it's recorded, or reported if this is the
+	/// last attempt.
+	async fn send(
+		&mut self,
+		job: &CurrentJob,
+		transport: &Transport,
+		breaker: &Breaker,
+		recorder: &Recorder,
+		retry_limit: RetryAfterLimit,
+		credentials: &Credentials,
+	) -> Sent {
+		let request = &self.request;
+		let client = match transport.client(&self.attempt.channel, request) {
+			Ok(client) => client,
+			Err(error) => {
+				self.error_kind = Some(ErrorKind::of(&error));
+				return Sent::Failed(Outcome::Error(error.to_string()), None);
+			}
+		};
+		// The request is kept intact for the hooks
+		let mut headers = request.headers.clone();
+		if let (true, Some(key)) = (request.forward_idempotency_key, &request.idempotency_key) {
+			match HeaderValue::from_str(key) {
+				Ok(key) => headers.insert(IDEMPOTENCY_KEY, key),
+				Err(error) => {
+					self.error_kind = Some(ErrorKind::Invalid);
+					return Sent::Failed(Outcome::Error(error.to_string()), None);
+				}
+			};
+		}
+		if let Some(context) = &self.trace_context {
+			context.inject(&mut headers);
+		}
+		if let Err(error) = credentials.secrets.resolve(request, &mut headers).await {
+			self.error_kind = Some(match error {
+				SecretError::Unknown(_) | SecretError::InvalidName(_) => ErrorKind::Invalid,
+				SecretError::Resolver(_) => ErrorKind::Request,
+			});
+			return Sent::Failed(Outcome::Error(error.to_string()), None);
+		}
+		let mut token = match credentials.auth.authorize(request, &client, &mut headers, None).await
+		{
+			Ok(token) => token,
+			Err(error) => {
+				self.error_kind = Some(match error {
+					AuthError::UnknownProvider(_) => ErrorKind::Invalid,
+					_ => ErrorKind::Request,
+				});
+				return Sent::Failed(Outcome::Error(error.to_string()), None);
+			}
+		};
+
+		#[cfg(any(feature = "tracing", feature = "metrics"))]
+		let started = Instant::now();
+		let response = loop {
+			// Signed last, as schemes may sign over headers
+			if let Err(error) = credentials.signer.sign(job.id(), request, &mut headers) {
+				self.error_kind = Some(match error {
+					SigningError::UnknownKey(_) => ErrorKind::Invalid,
+					SigningError::Scheme(_) => ErrorKind::Request,
+				});
+				return Sent::Failed(Outcome::Error(error.to_string()), None);
+			}
+			let mut builder = client
+				.request(request.method.clone(), request.url.clone())
+				.headers(headers.clone());
+			if let Some(body) = request.body.clone() {
+				builder = builder.body(body);
+			}
+			if let Some(timeout) = transport.timeout(request) {
+				builder = builder.timeout(timeout);
+			}
+			let response = builder.send();
+			#[cfg(feature = "tracing")]
+			let response = tracing::Instrument::instrument(response, self.span.clone());
+			let response = response.await;
+
+			// A rejected token is refreshed once, within the same attempt
+			let rejected = match (&response, token.take()) {
+				(Ok(response), Some(token)) if response.status() == StatusCode::UNAUTHORIZED => {
+					token
+				}
+				_ => break response,
+			};
+			let refreshed =
+				credentials.auth.authorize(request, &client, &mut headers, Some(&rejected)).await;
+			if let Err(error) = refreshed {
+				self.error_kind = Some(ErrorKind::Request);
+				return Sent::Failed(Outcome::Error(error.to_string()), None);
+			}
+		};
+		let status = response.as_ref().ok().map(reqwest::Response::status);
+		// A broken circuit breaker shouldn't keep the request from being delivered
+		breaker.record(job.pool(), request, status).await.ok();
+		let sent = match response {
+			Ok(response) if request.accepts(response.status()) => Sent::Accepted(response),
+			Ok(response) => {
+				let status = response.status();
+				let retry_after = retry_limit.retry_after(request, status, response.headers());
+				// The response is only reported if the request is given up on after it
+				let last = self.attempt.last
+					|| matches!(
+						next_attempt(request, &self.attempt, retry_after),
+						NextAttempt::Never
+					);
+				self.read(recorder, response, last).await;
+				Sent::Failed(Outcome::Response(status), retry_after)
+			}
+			Err(error) => {
+				self.error_kind = Some(ErrorKind::of(&error));
+				Sent::Failed(Outcome::Error(error.to_string()), None)
+			}
+		};
+		#[cfg(any(feature = "tracing", feature = "metrics"))]
+		self.observe(&sent, started.elapsed());
+		sent
+	}
+
+	/// Reads the body of the given response, as far as it's recorded in the
+	/// attempt history. If `reported` is set, also picks the parts of it which
+	/// are reported to the callback of the request, if it has one.
+	async fn read(&mut self, recorder: &Recorder, response: reqwest::Response, reported: bool) {
+		let Some(callback) = self.request.callback.as_ref().filter(|_| reported) else {
+			self.body = recorder.body(response).await;
+			return;
+		};
+		let (received, body) = callback.read(response, recorder.max_body_size()).await;
+		self.body = recorder.truncate(&body);
+		self.received = Some(received);
+	}
+
+	/// Records the result of sending the request in the span of the attempt,
+	/// and in the metrics.
+	#[cfg(any(feature = "tracing", feature = "metrics"))]
+	fn observe(&self, sent: &Sent, latency: Duration) {
+		let (outcome, accepted) = match sent {
+			Sent::Accepted(response) => (Outcome::Response(response.status()), true),
+			Sent::Failed(outcome, _) => (outcome.clone(), false),
+		};
+		#[cfg(feature = "tracing")]
+		trace::sent(&self.span, &outcome, accepted, latency);
+		#[cfg(feature = "metrics")]
+		metric::sent(&self.attempt.channel, &self.request, &outcome, accepted, latency);
+	}
+
+	/// Records the attempt with the given outcome in the attempt history, if
+	/// it's enabled.
+	async fn record<'e, E>(
+		&mut self,
+		recorder: &Recorder,
+		executor: E,
+		id: Uuid,
+		outcome: &Outcome,
+	) -> Result<(), sqlx::Error>
+	where
+		E: Executor<'e, Database = Postgres>,
+	{
+		let attempt = AttemptRecord {
+			id,
+			number: self.attempt.number,
+			started_at: self.started_at,
+			duration: self.started.elapsed(),
+			outcome: outcome.clone(),
+			error_kind: self.error_kind.filter(|_| matches!(outcome, Outcome::Error(_))),
+			body: self.body.take(),
+		};
+		recorder.record(executor, &attempt).await
+	}
+
+	/// Serializes the request which reports the given final state of the
+	/// request to its callback, if it has one, sealing it with the given
+	/// cipher.
+	fn report(
+		&mut self,
+		id: Uuid,
+		state: FinalState,
+		outcome: &Outcome,
+		cipher: &Cipher,
+	) -> Result<Option<Followup>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+		let Some(callback) = &self.request.callback else {
+			return Ok(None);
+		};
+		let report = Report::new(
+			id,
+			&self.attempt.channel,
+			state,
+			Some(outcome),
+			self.received.take(),
+			self.attempt.number,
+		);
+		report.request(callback, cipher).map(Some)
+	}
+
+	/// Records the outcome of a failed attempt. Moves the job to the dead
+	/// letter table if it has no attempts left, its retry policy gives up on it
+	/// or the request is invalid, and otherwise schedules the next attempt
+	/// according to the policy, no earlier than the server asked to be retried
+	/// at. Notifies the hooks of either, and the callback of the request if it
+	/// was moved. A job which was cancelled during the attempt is left alone,
+	/// as its cancellation was already reported to the callback. Returns
+	/// whether the job was moved.
+	async fn fail(
+		&mut self,
+		job: &mut CurrentJob,
+		recorder: &Recorder,
+		hooks: &Notifier,
+		cipher: &Cipher,
+		outcome: &Outcome,
+		retry_after: Option<Duration>,
+	) -> Result<bool, Box<dyn std::error::Error + Send + Sync + 'static>> {
+		self.record(recorder, job.pool(), job.id(), outcome).await?;
+		if status::record(job.pool(), job.id(), outcome, false).await? {
+			hooks.cancelled(job.id(), &self.attempt.channel, &self.request, outcome);
+			return Ok(false);
+		}
+		let followup = self.report(job.id(), FinalState::Dead, outcome, cipher)?;
+		let buried = if self.error_kind == Some(ErrorKind::Invalid) {
+			// Retrying can't fix the request, but it can be requeued once the
+			// client is fixed
+			dead_letter::bury(job, outcome, followup.as_ref()).await?
+		} else {
+			retry_or_bury(
+				job,
+				&self.request,
+				&self.attempt,
+				outcome,
+				retry_after,
+				followup.as_ref(),
+			)
+			.await?
+		};
+		#[cfg(feature = "tracing")]
+		trace::failed(&self.span, outcome, buried);
+		#[cfg(feature = "metrics")]
+		if buried {
+			metric::buried(&self.attempt.channel, &self.request);
+		}
+		hooks.failure(job.id(), &self.attempt.channel, &self.request, outcome, buried);
+		Ok(buried)
+	}
+}
+
+/// Moves the job to the dead letter table if running it failed with the given
+/// error before the outcome of its attempt was handled, and it has no attempts
+/// left, as sqlxmq would drop it otherwise. Returns whether the job was moved.
+async fn bury_on_error(
+	job: &mut CurrentJob,
+	error: &(dyn std::error::Error + Send + Sync + 'static),
+) -> bool {
+	// The error is likely a database one, so this may fail as well, in which
+	// case the job is left to sqlxmq
+	let outcome = Outcome::Error(error.to_string());
+	if status::record(job.pool(), job.id(), &outcome, false).await.is_err() {
+		return false;
 	}
+	dead_letter::bury_if_exhausted(job, &outcome, None).await.unwrap_or(false)
+}
+
+/// Returns the result a job finishes with after a failed attempt with the
+/// given outcome, which is an error if no response was received.
+fn failure(outcome: Outcome) -> JobResult {
+	match outcome {
+		Outcome::Error(error) => Err(error.into()),
+		Outcome::Response(_) => Ok(()),
+	}
+}
+
+/// Handles a failed attempt whose outcome was recorded. Moves the job to the
+/// dead letter table if it has no attempts left or its retry policy gives up
+/// on it, and otherwise schedules the next attempt according to the policy, no
+/// earlier than the server asked to be retried at. The report to the callback
+/// of the request, if it has one, is spawned if it's moved. Returns whether the
+/// job was moved.
+async fn retry_or_bury(
+	job: &mut CurrentJob,
+	request: &Request,
+	attempt: &Attempt,
+	outcome: &Outcome,
+	retry_after: Option<Duration>,
+	followup: Option<&Followup>,
+) -> Result<bool, sqlx::Error> {
+	if dead_letter::bury_if_exhausted(job, outcome, followup).await? {
+		return Ok(true);
+	}
+	match next_attempt(request, attempt, retry_after) {
+		NextAttempt::Backoff(None) => {}
+		NextAttempt::Backoff(Some(retry_after)) => retry::defer(job, retry_after).await?,
+		NextAttempt::After(delay) => retry::reschedule(job, delay).await?,
+		NextAttempt::Never => return dead_letter::bury(job, outcome, followup).await,
+	}
+	Ok(false)
+}
+
+/// When the next attempt to deliver a request is made after a failed one.
+enum NextAttempt {
+	/// After the backoff of the job, or the given time the server asked to be
+	/// retried after if that's later.
+	Backoff(Option<Duration>),
+	/// After the given delay set by the retry policy of the request.
+	After(Duration),
+	/// Never, since the retry policy gives up on the request.
+	Never,
+}
+
+/// Decides when to make the next attempt to deliver the given request after
+/// the given attempt failed, according to its retry policy, no earlier than
+/// the server asked to be retried at. Doesn't take into account whether the
+/// job has attempts left.
+fn next_attempt(
+	request: &Request,
+	attempt: &Attempt,
+	retry_after: Option<Duration>,
+) -> NextAttempt {
+	let Some(policy) = &request.retry_policy else {
+		return NextAttempt::Backoff(retry_after);
+	};
+
+	let mut delay = policy.backoff.delay(attempt.number, attempt.retry_delay);
+	if let Some(retry_after) = retry_after {
+		delay = delay.max(retry_after);
+	}
+	let next_attempt_at = chrono::Duration::from_std(delay)
+		.ok()
+		.and_then(|delay| Utc::now().checked_add_signed(delay));
+	let allowed = match next_attempt_at {
+		Some(next_attempt_at) => policy.allows(attempt.created_at, next_attempt_at),
+		None => policy.max_duration.is_none(),
+	};
+	if allowed {
+		NextAttempt::After(delay)
+	} else {
+		NextAttempt::Never
+	}
+}
+
+/// Moves the given job, whose request can't be opened because of the given
+/// error, to the dead letter table right away, recording it as a failed
+/// attempt. Retrying can't open it, but the dead letter keeps the sealed
+/// request, so it can be requeued once the key it was sealed with is restored.
+/// Neither the hooks nor the callback of the request can be notified without
+/// it.
+async fn bury_unreadable(
+	job: &mut CurrentJob,
+	recorder: &Recorder,
+	error: &SpawnError,
+) -> Result<Start, sqlx::Error> {
+	let Some(attempt) = status::attempt_started(job).await? else {
+		return Ok(Start::Cancelled);
+	};
+	let outcome = Outcome::Error(error.to_string());
+	let record = AttemptRecord {
+		id: job.id(),
+		number: attempt.number,
+		started_at: Utc::now(),
+		duration: Duration::ZERO,
+		outcome: outcome.clone(),
+		error_kind: Some(ErrorKind::Invalid),
+		body: None,
+	};
+	recorder.record(job.pool(), &record).await?;
+	if status::record(job.pool(), job.id(), &outcome, false).await? {
+		return Ok(Start::Cancelled);
+	}
+	dead_letter::bury(job, &outcome, None).await?;
+	Ok(Start::Unreadable)
+}
 
+/// Defers the given job by the given amount of time, giving back the attempt
+/// it was picked up with.
+async fn postpone(job: &CurrentJob, wait: Duration) -> Result<(), sqlx::Error> {
+	// Picking up a job uses up an attempt and doubles its retry backoff, which
+	// is reverted here.
+	sqlx::query(
+		"UPDATE mq_msgs SET
+			attempt_at = NOW() + $2 * INTERVAL '1 microsecond',
+			attempts = attempts + 1,
+			retry_backoff = retry_backoff / 2
+		WHERE id = $1",
+	)
+	.bind(job.id())
+	.bind(i64::try_from(wait.as_micros()).unwrap_or(i64::MAX))
+	.execute(job.pool())
+	.await?;
+	#[cfg(feature = "tracing")]
+	trace::postponed(job, wait);
 	Ok(())
 }
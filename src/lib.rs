@@ -23,7 +23,7 @@
 //! interface with the background task directly.
 //!
 //! ```no_run
-//! # async fn test(pool: sqlx::Pool<sqlx::Postgres>) -> Result<(), sqlx::Error> {
+//! # async fn test(pool: sqlx::Pool<sqlx::Postgres>) -> Result<(), requeuest::error::ClientError> {
 //! use requeuest::{Client, client::Channels};
 //!
 //! let client = Client::new(pool, Channels::List(&["my_service"])).await?;
@@ -31,6 +31,17 @@
 //! # }
 //! ```
 //!
+//! Settings such as the default timeout of each attempt to send a request can
+//! be configured by constructing the client with [`Client::builder`] instead.
+//! Requests can override the timeouts with
+//! [`Request::timeout`](crate::Request::timeout) and
+//! [`Request::connect_timeout`](crate::Request::connect_timeout). An attempt
+//! which times out counts as a failed attempt, and is retried like any other.
+//! Proxies, certificates and other connection settings are configured with
+//! [`ClientBuilder::http_client`](crate::client::ClientBuilder::http_client),
+//! or per channel with
+//! [`ClientBuilder::channel_http_client`](crate::client::ClientBuilder::channel_http_client).
+//!
 //! After the client has been constructed, you can begin spawning jobs. Here we
 //! send a get request to an example address:
 //!
@@ -57,33 +68,206 @@
 //! # }
 //! ```
 //!
+//! Many requests can be spawned at once with [`Client::spawn_batch`], which
+//! queues all of them in a single statement.
+//!
+//! Requests can also be spawned inside of an existing transaction with
+//! [`Client::spawn_in`] and [`Client::spawn_storing_in`], so they're only
+//! queued if the transaction commits.
+//!
+//! Requests which might be spawned more than once, e.g. when a process crashes
+//! before recording that it spawned one, can be given an
+//! [`idempotency_key`](crate::Request::idempotency_key). Spawning a request
+//! with a key already used within the idempotency window, configured with
+//! [`ClientBuilder::idempotency_window`](crate::client::ClientBuilder::idempotency_window),
+//! returns the UUID of the earlier job instead of queueing it again. The key
+//! can also be sent to the server in an `Idempotency-Key` header by setting
+//! [`forward_idempotency_key`](crate::Request::forward_idempotency_key).
+//!
 //! Note that the `spawn_returning` method *will* wait indefinitely (or to be
 //! precise, roughly 10^293 years) until a successful response is received, so
 //! this will wait forever if a request is sent to e.g. an unregistered domain,
-//! or sends data to an API which will always result in a non-200 response code.
+//! or sends data to an API which will always result in a non-200 response code,
+//! unless the request is given an expiry with
+//! [`Request::expire_after`](crate::Request::expire_after).
+//!
+//! Responses are stored in the database once received, so if the process
+//! which spawned a request restarts, or you don't want to wait for the
+//! response right away, it can still be collected later with
+//! [`Client::await_response`], using the UUID returned by
+//! [`Client::spawn_storing`].
+//!
+//! By default, failed requests are retried 100 000 times, and the wait before
+//! each retry doubles. A [`RetryPolicy`](crate::retry::RetryPolicy) can be set
+//! on a request with [`Request::retry_policy`](crate::Request::retry_policy),
+//! or for all requests spawned on a channel with
+//! [`ClientBuilder::retry_policy`](crate::client::ClientBuilder::retry_policy),
+//! to use a different backoff, or to give up after fewer attempts or a
+//! maximum amount of time. When a server responds with a 429 or 503 status
+//! and a `Retry-After` header, the next attempt waits at least until the time
+//! it asks for, up to the policy's
+//! [`max_retry_after`](crate::retry::RetryPolicy::max_retry_after), or an
+//! hour by default, see
+//! [`ClientBuilder::max_retry_after`](crate::client::ClientBuilder::max_retry_after).
+//!
+//! The rate at which requests are sent can be limited per channel with
+//! [`ClientBuilder::channel_rate_limit`](crate::client::ClientBuilder::channel_rate_limit),
+//! or per host with
+//! [`ClientBuilder::host_rate_limit`](crate::client::ClientBuilder::host_rate_limit).
+//! Requests exceeding a limit are deferred in the queue, and processes using
+//! the same database share the same limits.
+//!
+//! With [`ClientBuilder::circuit_breaker`](crate::client::ClientBuilder::circuit_breaker),
+//! the job runner stops sending requests to a host for a while after too many
+//! of them failed in a row, and then probes whether the host has recovered.
+//! The state of each host's circuit can be looked up with [`Client::circuit`],
+//! and changes to it are broadcast through [`Client::circuit_events`].
+//!
+//! What happened to a spawned request can be looked up with
+//! [`Client::status`], which reports whether it's still queued, was delivered,
+//! or ran out of attempts, along with the outcome of its last attempt.
+//! [`Client::queue_stats`] counts the queued jobs of each channel by their
+//! state, e.g. to export them as gauges.
+//!
+//! To continue a distributed trace at the server receiving a request, build
+//! the client with
+//! [`ClientBuilder::propagate_trace_context`](crate::client::ClientBuilder::propagate_trace_context),
+//! and spawn the request in a
+//! [`TraceContext::scope`](crate::trace_context::TraceContext::scope). The
+//! context is stored with the job, and sent in `traceparent` and `tracestate`
+//! headers.
+//!
+//! Only the outcome of the latest attempt is kept by default. To record every
+//! attempt along with its duration and the start of the response body, run
+//! [`migrate_attempt_history`] and build the client with
+//! [`ClientBuilder::attempt_history`](crate::client::ClientBuilder::attempt_history).
+//! The attempts of a job can then be looked up with [`Client::attempts`].
+//!
+//! To react to requests being delivered or given up on without waiting for
+//! them, register [`Hooks`](crate::hooks::Hooks) with
+//! [`ClientBuilder::hooks`](crate::client::ClientBuilder::hooks). To let
+//! another service know instead, give the request a
+//! [`Callback`](crate::callback::Callback), which is sent a JSON report of its
+//! final state through the queue.
+//!
+//! To sign requests, e.g. webhooks, register a
+//! [`SigningKey`](crate::signing::SigningKey) with
+//! [`ClientBuilder::signing_key`](crate::client::ClientBuilder::signing_key),
+//! and refer to it by name with [`Request::signing_key`]. Each attempt is
+//! signed with a fresh timestamp.
+//!
+//! Credentials in headers are stored in the queue along with the rest of the
+//! request. To keep them out of it, set them with [`Request::secret_header`] to
+//! a [`SecretRef`](crate::secret::SecretRef), which the
+//! [`SecretResolver`](crate::secret::SecretResolver) registered with
+//! [`ClientBuilder::secret_resolver`](crate::client::ClientBuilder::secret_resolver)
+//! resolves at each attempt.
+//!
+//! For APIs which take OAuth2 bearer tokens, register an
+//! [`AuthProvider`](crate::auth::AuthProvider), e.g. one using the
+//! [client credentials grant](crate::auth::ClientCredentials), with
+//! [`ClientBuilder::auth_provider`](crate::client::ClientBuilder::auth_provider),
+//! and refer to it by name with [`Request::auth_provider`]. Tokens are cached
+//! until they expire, and when a server rejects one, it's refreshed and the
+//! request sent again right away, without using up an attempt.
+//!
+//! To keep request bodies and headers from being readable in the database,
+//! build the client with
+//! [`ClientBuilder::encryption`](crate::client::ClientBuilder::encryption).
+//! Requests are then sealed with AES-256-GCM before being stored, and keys can
+//! be rotated without losing access to the requests already queued.
+//!
+//! Requests which run out of attempts are moved to a dead letter table, where
+//! they can be inspected with [`Client::dead_letters`], and sent again with
+//! [`Client::requeue_dead_letter`] once the receiving end has recovered.
 //!
 //! # Features
 //! This crate has the following features:
 //! * `http`: Enable conversion of requests from the [`http`] crate
+//! * `tracing`: Emit [`tracing`] spans for spawning requests and for each
+//!   attempt to send them, which share the job's ID and record the trace
+//!   context the request was spawned in. Outside of a
+//!   [`TraceContext::scope`](crate::trace_context::TraceContext::scope), that's
+//!   the OpenTelemetry context of the current span, see [`trace_context`].
+//!   Sensitive headers are redacted, see
+//!   [`ClientBuilder::redacted_headers`](crate::client::ClientBuilder::redacted_headers)
+//! * `metrics`: Record the number, results and durations of attempts to send
+//!   requests through the [`metrics`] facade, for whichever exporter the
+//!   application installs
 //! * Async runtime and TLS implementation for [`sqlx`]:
 //!     * Any of `runtime-{tokio,actix,async-std}-{rustls,native-tls}`
 
 #![doc(html_logo_url = "https://github.com/famedly/requeuest/blob/main/logo.svg")]
 #![deny(missing_docs)]
 
+pub mod auth;
+pub mod callback;
+pub mod circuit;
 pub mod client;
+pub mod dead_letter;
+pub mod encryption;
 pub mod error;
+pub mod history;
+pub mod hooks;
+pub(crate) mod idempotency;
 pub(crate) mod job;
+#[cfg(feature = "metrics")]
+pub(crate) mod metric;
+pub mod rate_limit;
 pub mod request;
+pub mod response;
+pub mod retry;
+pub mod secret;
+pub mod signing;
+pub mod status;
+#[cfg(feature = "tracing")]
+pub(crate) mod trace;
+pub mod trace_context;
+pub mod transport;
+
+use std::collections::HashSet;
 
 pub use client::Client;
 pub use request::Request;
 pub use reqwest::{self, header::HeaderMap, Method};
+pub use response::Response;
+use sqlx::migrate::{Migrate, Migrator};
 pub use sqlx::{Pool, Postgres};
 pub use url::{ParseError, Url};
 pub use uuid::Uuid;
 
 /// Runs the SQL migrations this library needs.
 pub async fn migrate(pool: &Pool<Postgres>) -> Result<(), sqlx::migrate::MigrateError> {
-	sqlx::migrate!().run(pool).await
+	run_migrations(sqlx::migrate!(), &sqlx::migrate!("migrations/attempt_history"), pool).await
+}
+
+/// Runs the opt-in SQL migrations of the [attempt history](history), after the
+/// ones run by [`migrate`].
+pub async fn migrate_attempt_history(
+	pool: &Pool<Postgres>,
+) -> Result<(), sqlx::migrate::MigrateError> {
+	run_migrations(sqlx::migrate!("migrations/attempt_history"), &sqlx::migrate!(), pool).await
+}
+
+/// Runs the migrations of the given migrator. The migrations of the other given
+/// migrator are applied to the same database, so they aren't reported as
+/// missing, but any other applied migration which the migrator doesn't know
+/// still is.
+async fn run_migrations(
+	mut migrator: Migrator,
+	other: &Migrator,
+	pool: &Pool<Postgres>,
+) -> Result<(), sqlx::migrate::MigrateError> {
+	let known: HashSet<i64> =
+		migrator.iter().chain(other.iter()).map(|migration| migration.version).collect();
+	let mut conn = pool.acquire().await?;
+	conn.ensure_migrations_table().await?;
+	let applied = conn.list_applied_migrations().await?;
+	if let Some(unknown) = applied.iter().find(|migration| !known.contains(&migration.version)) {
+		return Err(sqlx::migrate::MigrateError::VersionMissing(unknown.version));
+	}
+	drop(conn);
+
+	migrator.set_ignore_missing(true);
+	migrator.run(pool).await
 }
@@ -0,0 +1,76 @@
+//! Metrics of delivery attempts, recorded through the [`metrics`] facade when
+//! the `metrics` feature is enabled. Any exporter installed by the application,
+//! such as a Prometheus or OpenTelemetry one, picks them up.
+
+use std::time::Duration;
+
+use metrics::Unit;
+
+use crate::{request::Request, status::Outcome};
+
+/// The number of attempts to send a request, by channel, host and result.
+const ATTEMPTS: &str = "requeuest_attempts_total";
+/// How long attempts to send a request took, by channel and host.
+const ATTEMPT_DURATION: &str = "requeuest_attempt_duration_seconds";
+/// The number of requests moved to the dead letter table, by channel and host.
+const DEAD_LETTERS: &str = "requeuest_dead_letters_total";
+/// The number of requests which expired before being delivered, by host.
+const EXPIRED: &str = "requeuest_expired_total";
+
+/// Describes the recorded metrics to the installed recorder.
+pub(crate) fn describe() {
+	metrics::describe_counter!(ATTEMPTS, Unit::Count, "Attempts to send a request");
+	metrics::describe_histogram!(
+		ATTEMPT_DURATION,
+		Unit::Seconds,
+		"How long attempts to send a request took"
+	);
+	metrics::describe_counter!(DEAD_LETTERS, Unit::Count, "Requests which were given up on");
+	metrics::describe_counter!(EXPIRED, Unit::Count, "Requests which expired undelivered");
+}
+
+/// Returns the host label of the given request.
+fn host(request: &Request) -> String {
+	request.url.host_str().unwrap_or_default().to_owned()
+}
+
+/// Records the result of an attempt to send the given request, where
+/// `accepted` is whether the response has an accepted status code.
+pub(crate) fn sent(
+	channel: &str,
+	request: &Request,
+	outcome: &Outcome,
+	accepted: bool,
+	latency: Duration,
+) {
+	let result = match outcome {
+		_ if accepted => "delivered",
+		Outcome::Response(_) => "rejected",
+		Outcome::Error(_) => "error",
+	};
+	metrics::counter!(
+		ATTEMPTS,
+		"channel" => channel.to_owned(),
+		"host" => host(request),
+		"result" => result,
+	)
+	.increment(1);
+	metrics::histogram!(
+		ATTEMPT_DURATION,
+		"channel" => channel.to_owned(),
+		"host" => host(request),
+	)
+	.record(latency.as_secs_f64());
+}
+
+/// Records that the given request was given up on and moved to the dead
+/// letter table.
+pub(crate) fn buried(channel: &str, request: &Request) {
+	metrics::counter!(DEAD_LETTERS, "channel" => channel.to_owned(), "host" => host(request))
+		.increment(1);
+}
+
+/// Records that the given request expired before being delivered.
+pub(crate) fn expired(request: &Request) {
+	metrics::counter!(EXPIRED, "host" => host(request)).increment(1);
+}
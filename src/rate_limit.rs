@@ -0,0 +1,229 @@
+//! Rate limits cap how many requests job runners send per channel or per host.
+//! Their state is kept in the database, so every process sharing it takes from
+//! the same buckets. Jobs which exceed a limit are deferred in the queue,
+//! without using up one of their attempts.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use sqlx::{PgPool, Row};
+use sqlxmq::CurrentJob;
+
+use crate::{error::ClientError, request::Request};
+
+/// A token bucket which allows a number of requests per period of time.
+///
+/// # Example
+/// ```
+/// use std::time::Duration;
+///
+/// use requeuest::rate_limit::RateLimit;
+///
+/// // 10 requests per second, without allowing bursts
+/// let limit = RateLimit::per_second(10).burst(1);
+/// // 100 requests per minute
+/// let limit = RateLimit::new(100, Duration::from_secs(60));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[must_use]
+pub struct RateLimit {
+	/// The number of requests allowed per period, which has to be at least
+	/// one.
+	pub requests: u32,
+	/// The period of time the requests are allowed in, which mustn't be empty.
+	pub per: Duration,
+	/// How many requests may be sent at once after a quiet period, which has
+	/// to be at least one. Defaults to the number of requests allowed per
+	/// period.
+	pub burst: u32,
+}
+
+impl RateLimit {
+	/// Constructs a rate limit which allows the given number of requests per
+	/// period of time.
+	pub fn new(requests: u32, per: Duration) -> Self {
+		Self { requests, per, burst: requests }
+	}
+
+	/// Constructs a rate limit which allows the given number of requests per
+	/// second.
+	pub fn per_second(requests: u32) -> Self {
+		Self::new(requests, Duration::from_secs(1))
+	}
+
+	/// Sets how many requests may be sent at once after a quiet period.
+	pub fn burst(mut self, burst: u32) -> Self {
+		self.burst = burst;
+		self
+	}
+
+	/// Returns whether the limit allows any requests.
+	fn is_valid(&self) -> bool {
+		self.requests > 0 && self.burst > 0 && !self.per.is_zero()
+	}
+
+	/// Returns the time between two requests at the allowed rate.
+	fn interval(&self) -> Duration {
+		self.per / self.requests
+	}
+
+	/// Returns how far ahead of the allowed rate requests may get.
+	fn tolerance(&self) -> Duration {
+		self.interval().saturating_mul(self.burst - 1)
+	}
+}
+
+/// The rate limits a job runner enforces.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RateLimiter(Arc<Limits>);
+
+/// The rate limits of a [`RateLimiter`].
+#[derive(Debug, Default)]
+pub(crate) struct Limits {
+	/// The rate limits of requests spawned on each channel.
+	pub channels: HashMap<String, RateLimit>,
+	/// The rate limits of requests sent to each host.
+	pub hosts: HashMap<String, RateLimit>,
+}
+
+impl RateLimiter {
+	/// Constructs a rate limiter which enforces the given limits, which have
+	/// to allow requests.
+	pub fn new(limits: Limits) -> Result<Self, ClientError> {
+		let channels = limits.channels.iter().map(|(channel, limit)| ("channel", channel, limit));
+		let hosts = limits.hosts.iter().map(|(host, limit)| ("host", host, limit));
+		if let Some((kind, name, _)) = channels.chain(hosts).find(|(_, _, limit)| !limit.is_valid())
+		{
+			return Err(ClientError::InvalidRateLimit(format!("{kind} {name}")));
+		}
+		Ok(Self(Arc::new(limits)))
+	}
+
+	/// Takes a token from each bucket the request of the given job falls
+	/// under. Returns how long to wait before trying again if one of them is
+	/// empty, in which case no token is taken from any of them.
+	pub async fn acquire(
+		&self,
+		job: &CurrentJob,
+		request: &Request,
+	) -> Result<Option<Duration>, sqlx::Error> {
+		let mut buckets = Vec::new();
+		if !self.0.channels.is_empty() {
+			let channel: String =
+				sqlx::query_scalar("SELECT channel_name FROM mq_msgs WHERE id = $1")
+					.bind(job.id())
+					.fetch_one(job.pool())
+					.await?;
+			if let Some(limit) = self.0.channels.get(&channel) {
+				buckets.push((format!("channel:{channel}"), limit));
+			}
+		}
+		let host = request.url.host_str().and_then(|host| self.0.hosts.get_key_value(host));
+		if let Some((host, limit)) = host {
+			buckets.push((format!("host:{host}"), limit));
+		}
+		if buckets.is_empty() {
+			return Ok(None);
+		}
+		take(job.pool(), &buckets).await
+	}
+}
+
+/// Converts a duration to microseconds, saturating on overflow.
+fn micros(duration: Duration) -> i64 {
+	i64::try_from(duration.as_micros()).unwrap_or(i64::MAX)
+}
+
+/// Takes a token from each of the buckets with the given keys, if all of them
+/// have one. Returns how long to wait until all of them have a token
+/// otherwise.
+///
+/// Buckets are tracked as the time the next request would be allowed at if no
+/// bursts were allowed, which is pushed forward by one interval per request.
+async fn take(
+	pool: &PgPool,
+	buckets: &[(String, &RateLimit)],
+) -> Result<Option<Duration>, sqlx::Error> {
+	let keys: Vec<&str> = buckets.iter().map(|(key, _)| key.as_str()).collect();
+	let mut tx = pool.begin().await?;
+	sqlx::query(
+		"INSERT INTO requeuest_rate_limits (key, allowed_at)
+		SELECT UNNEST($1::TEXT[]), NOW()
+		ON CONFLICT (key) DO NOTHING",
+	)
+	.bind(&keys)
+	.execute(&mut *tx)
+	.await?;
+	// Locked in a consistent order, so concurrent jobs can't deadlock
+	let rows = sqlx::query(
+		"SELECT key, (EXTRACT(EPOCH FROM GREATEST(allowed_at - NOW(), INTERVAL '0')) * 1000000)::BIGINT AS ahead
+		FROM requeuest_rate_limits
+		WHERE key = ANY($1)
+		ORDER BY key
+		FOR UPDATE",
+	)
+	.bind(&keys)
+	.fetch_all(&mut *tx)
+	.await?;
+
+	let mut wait = 0;
+	for row in &rows {
+		let key: &str = row.try_get("key")?;
+		let ahead: i64 = row.try_get("ahead")?;
+		if let Some((_, limit)) = buckets.iter().find(|(bucket, _)| bucket == key) {
+			wait = wait.max(ahead - micros(limit.tolerance()));
+		}
+	}
+	if wait > 0 {
+		tx.rollback().await?;
+		let wait = u64::try_from(wait).unwrap_or_default().max(1000);
+		return Ok(Some(Duration::from_micros(wait)));
+	}
+
+	let intervals: Vec<i64> = buckets.iter().map(|(_, limit)| micros(limit.interval())).collect();
+	sqlx::query(
+		"UPDATE requeuest_rate_limits AS limits
+		SET allowed_at = GREATEST(limits.allowed_at, NOW()) + taken.interval * INTERVAL '1 microsecond'
+		FROM UNNEST($1::TEXT[], $2::BIGINT[]) AS taken (key, interval)
+		WHERE limits.key = taken.key",
+	)
+	.bind(&keys)
+	.bind(&intervals)
+	.execute(&mut *tx)
+	.await?;
+	tx.commit().await?;
+	Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::{Limits, RateLimit, RateLimiter};
+	use crate::error::ClientError;
+
+	#[test]
+	fn bucket() {
+		let limit = RateLimit::per_second(4);
+		assert_eq!(limit.interval(), Duration::from_millis(250));
+		assert_eq!(limit.tolerance(), Duration::from_millis(750));
+
+		let limit = limit.burst(1);
+		assert_eq!(limit.tolerance(), Duration::ZERO);
+
+		assert!(limit.is_valid());
+		assert!(!RateLimit::new(0, Duration::from_secs(60)).is_valid());
+		assert!(!RateLimit::per_second(4).burst(0).is_valid());
+		assert!(!RateLimit::new(4, Duration::ZERO).is_valid());
+	}
+
+	#[test]
+	fn invalid() {
+		let mut limits = Limits::default();
+		limits.channels.insert("webhooks".to_owned(), RateLimit::per_second(10));
+		limits.hosts.insert("example.com".to_owned(), RateLimit::new(0, Duration::from_secs(1)));
+		match RateLimiter::new(limits) {
+			Err(ClientError::InvalidRateLimit(key)) => assert_eq!(key, "host example.com"),
+			_ => panic!("Rate limit without requests was accepted"),
+		}
+	}
+}
@@ -1,15 +1,25 @@
 //! Contains the definition of the request which gets (de)serialized and sent to
 //! the database
 
-use std::{collections::HashSet, convert::TryInto};
-
-use reqwest::{header::HeaderMap, Method, StatusCode};
+use std::{
+	collections::{BTreeMap, HashSet},
+	convert::TryInto,
+	time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use reqwest::{
+	header::{HeaderMap, HeaderName},
+	Method, StatusCode,
+};
 use serde::{Deserialize, Serialize};
 use typed_builder::TypedBuilder;
 use url::Url;
 
+use crate::{callback::Callback, retry::RetryPolicy, secret::SecretRef};
+
 /// An HTTP request to be sent through the job queue.
-#[derive(Serialize, Deserialize, Debug, TypedBuilder)]
+#[derive(Serialize, Deserialize, Debug, Clone, TypedBuilder)]
 #[must_use]
 pub struct Request {
 	/// The url to send the request to.
@@ -28,6 +38,61 @@ pub struct Request {
 	#[serde(default = "default_accepted_responses")]
 	#[builder(default=default_accepted_responses())]
 	pub accept_responses: HashSet<AcceptedResponse>,
+	/// The point in time after which no further attempts are made to deliver
+	/// the request.
+	#[serde(default)]
+	#[builder(default, setter(strip_option))]
+	pub expires_at: Option<DateTime<Utc>>,
+	/// How long a single attempt to send the request may take before it's
+	/// considered failed. Falls back to the client's default if unset.
+	#[serde(default)]
+	#[builder(default, setter(strip_option))]
+	pub timeout: Option<Duration>,
+	/// How long connecting to the server may take during a single attempt
+	/// before it's considered failed, rounded up to the next tenth of a
+	/// second. Falls back to the client's default if unset.
+	#[serde(default)]
+	#[builder(default, setter(strip_option))]
+	pub connect_timeout: Option<Duration>,
+	/// How long to wait between attempts, and when to give up on the request.
+	/// Falls back to the policy of the channel the request is spawned on, or
+	/// sqlxmq's doubling backoff if neither is set.
+	#[serde(default)]
+	#[builder(default, setter(strip_option))]
+	pub retry_policy: Option<RetryPolicy>,
+	/// A key which deduplicates spawned requests. Spawning a request with the
+	/// same key as one spawned within the client's idempotency window returns
+	/// the UUID of the earlier job instead of queueing the request again.
+	#[serde(default)]
+	#[builder(default, setter(strip_option, into))]
+	pub idempotency_key: Option<String>,
+	/// Whether to send the idempotency key to the server in an
+	/// `Idempotency-Key` header, replacing any header of that name. Spawning
+	/// the request fails if the key isn't a valid header value.
+	#[serde(default)]
+	#[builder(default)]
+	pub forward_idempotency_key: bool,
+	/// Where to report the final state of the request to, once it's
+	/// delivered, given up on or expires.
+	#[serde(default)]
+	#[builder(default, setter(strip_option))]
+	pub callback: Option<Callback>,
+	/// The name of the client's signing key to sign the request with at each
+	/// attempt.
+	#[serde(default)]
+	#[builder(default, setter(strip_option, into))]
+	pub signing_key: Option<String>,
+	/// Headers whose values are secrets, by the name of the header. Only the
+	/// reference to the secret is stored with the request, and its value is
+	/// resolved by the client's secret resolver at each attempt.
+	#[serde(default)]
+	#[builder(default)]
+	pub secret_headers: BTreeMap<String, SecretRef>,
+	/// The name of the client's auth provider whose bearer token is sent in
+	/// the `Authorization` header.
+	#[serde(default)]
+	#[builder(default, setter(strip_option, into))]
+	pub auth_provider: Option<String>,
 }
 
 /// The kinds of categories of response codes which a response can accept
@@ -70,11 +135,67 @@ fn default_accepted_responses() -> HashSet<AcceptedResponse> {
 	[AcceptedResponse::Success].into_iter().collect()
 }
 
+/// The prefix of payloads stored in a versioned layout. Read as the length of
+/// the URL an unversioned payload starts with, it would exceed any URL which
+/// can actually be stored.
+const PAYLOAD_MAGIC: &[u8] = b"RQREQ";
+/// The version of the layout requests are stored in, which has to be bumped
+/// whenever a field is added to [`Request`], keeping the previous layouts
+/// readable.
+const PAYLOAD_VERSION: u8 = 1;
+
+/// The layout requests were stored in before payloads were versioned, which
+/// jobs and dead letters stored by earlier versions of this crate still have.
+#[derive(Deserialize)]
+struct LegacyRequest {
+	/// The url to send the request to.
+	url: Url,
+	/// The body of the request.
+	body: Option<Vec<u8>>,
+	/// The HTTP method to connect with
+	#[serde(with = "http_serde::method")]
+	method: Method,
+	/// The HTTP headers to set for the request.
+	#[serde(with = "http_serde::header_map")]
+	headers: HeaderMap,
+	/// A set of HTTP response codes which won't cause a retry.
+	accept_responses: HashSet<AcceptedResponse>,
+}
+
+impl From<LegacyRequest> for Request {
+	fn from(legacy: LegacyRequest) -> Self {
+		let mut request = Request::builder()
+			.url(legacy.url)
+			.method(legacy.method)
+			.headers(legacy.headers)
+			.accept_responses(legacy.accept_responses)
+			.build();
+		request.body = legacy.body;
+		request
+	}
+}
+
 /// Return builder type for methods with predefined method
-type WithUrlAndMethodBuilder = RequestBuilder<((Url,), (), (Method,), (), ())>;
+type WithUrlAndMethodBuilder =
+	RequestBuilder<((Url,), (), (Method,), (), (), (), (), (), (), (), (), (), (), (), ())>;
 /// Return builder type for methods with predefined method and body
-type WithUrlAndBodyAndMethodBuilder =
-	RequestBuilder<((Url,), (Option<Vec<u8>>,), (Method,), (), ())>;
+type WithUrlAndBodyAndMethodBuilder = RequestBuilder<(
+	(Url,),
+	(Option<Vec<u8>>,),
+	(Method,),
+	(),
+	(),
+	(),
+	(),
+	(),
+	(),
+	(),
+	(),
+	(),
+	(),
+	(),
+	(),
+)>;
 
 impl Request {
 	/// Constructs a `GET` request builder.
@@ -132,6 +253,86 @@ impl Request {
 		Ok(Request::builder().method(Method::PUT).url(url.try_into()?).body(body))
 	}
 
+	/// Sets the request to expire once the given amount of time has passed
+	/// from now.
+	///
+	/// # Example
+	/// ```
+	/// # use requeuest::Request;
+	/// # use std::time::Duration;
+	/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+	/// let request = Request::get("https://example.com")?.build();
+	/// let request = request.expire_after(Duration::from_secs(60));
+	/// assert!(!request.is_expired());
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn expire_after(mut self, ttl: Duration) -> Self {
+		self.expires_at =
+			chrono::Duration::from_std(ttl).ok().and_then(|ttl| Utc::now().checked_add_signed(ttl));
+		self
+	}
+
+	/// Sets the header with the given name to the value of the given secret,
+	/// which is resolved when the request is sent, replacing a header of the
+	/// same name.
+	///
+	/// # Example
+	/// ```
+	/// # use requeuest::{reqwest::header::AUTHORIZATION, secret::SecretRef, Request};
+	/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+	/// let request = Request::get("https://example.com")?.build();
+	/// let request = request.secret_header(AUTHORIZATION, SecretRef::new("partner_x_token"));
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn secret_header(mut self, name: HeaderName, secret: SecretRef) -> Self {
+		self.headers.remove(&name);
+		self.secret_headers.insert(name.as_str().to_owned(), secret);
+		self
+	}
+
+	/// Returns true if a response with the given status code is accepted as
+	/// the request having been delivered.
+	#[must_use]
+	pub fn accepts(&self, status: StatusCode) -> bool {
+		self.accept_responses.iter().any(|accepted| accepted.accepts(status))
+	}
+
+	/// Returns true if the request has an expiry which has passed.
+	#[must_use]
+	pub fn is_expired(&self) -> bool {
+		self.expires_at.is_some_and(|expires_at| expires_at <= Utc::now())
+	}
+
+	/// Serializes the request into the payload it's stored in, prefixed with
+	/// the version of its layout.
+	pub(crate) fn to_payload(&self) -> Result<Vec<u8>, bincode::Error> {
+		let mut payload = PAYLOAD_MAGIC.to_vec();
+		payload.push(PAYLOAD_VERSION);
+		bincode::serialize_into(&mut payload, self)?;
+		Ok(payload)
+	}
+
+	/// Deserializes a request from the payload it was stored in, in any of the
+	/// layouts it may have been stored in.
+	pub(crate) fn from_payload(payload: &[u8]) -> Result<Self, bincode::Error> {
+		let Some(versioned) = payload.strip_prefix(PAYLOAD_MAGIC) else {
+			return Ok(bincode::deserialize::<LegacyRequest>(payload)?.into());
+		};
+		match versioned.split_first() {
+			Some((&PAYLOAD_VERSION, request)) => bincode::deserialize(request),
+			Some((version, _)) => Err(bincode::ErrorKind::Custom(format!(
+				"Unknown request payload version {}",
+				version
+			))
+			.into()),
+			None => {
+				Err(bincode::ErrorKind::Custom("Missing request payload version".into()).into())
+			}
+		}
+	}
+
 	/// Convert a reqwest request into a requeuest request.
 	pub fn from_reqwest(mut foreign: reqwest::Request) -> Self {
 		Self {
@@ -140,6 +341,16 @@ impl Request {
 			method: std::mem::take(foreign.method_mut()),
 			headers: std::mem::take(foreign.headers_mut()),
 			accept_responses: default_accepted_responses(),
+			expires_at: None,
+			timeout: None,
+			connect_timeout: None,
+			retry_policy: None,
+			idempotency_key: None,
+			forward_idempotency_key: false,
+			callback: None,
+			signing_key: None,
+			secret_headers: BTreeMap::new(),
+			auth_provider: None,
 		}
 	}
 
@@ -165,6 +376,16 @@ impl Request {
 			method: parts.method,
 			headers: parts.headers,
 			accept_responses: default_accepted_responses(),
+			expires_at: None,
+			timeout: None,
+			connect_timeout: None,
+			retry_policy: None,
+			idempotency_key: None,
+			forward_idempotency_key: false,
+			callback: None,
+			signing_key: None,
+			secret_headers: BTreeMap::new(),
+			auth_provider: None,
 		})
 	}
 
@@ -190,6 +411,9 @@ impl Request {
 #[cfg(test)]
 mod tests {
 	#![allow(clippy::unwrap_used)]
+	use std::time::Duration;
+
+	use chrono::Utc;
 	use reqwest::{
 		header::{HeaderMap, HeaderValue, AUTHORIZATION},
 		Method, StatusCode,
@@ -197,6 +421,10 @@ mod tests {
 	use url::ParseError;
 
 	use super::Request;
+	use crate::{
+		retry::{Backoff, RetryPolicy},
+		secret::SecretRef,
+	};
 
 	/// Convenience function to convert a u16 to status code and unwrap the
 	/// result
@@ -223,15 +451,55 @@ mod tests {
 
 	#[test]
 	fn serialization() {
-		let request =
-			Request::post("https://example.com/", b"Some cool data".to_vec()).unwrap().build();
-		let serialized = bincode::serialize(&request).unwrap();
-		let deserialized: Request = bincode::deserialize(&serialized).unwrap();
+		let request = Request::post("https://example.com/", b"Some cool data".to_vec())
+			.unwrap()
+			.timeout(Duration::from_secs(10))
+			.retry_policy(RetryPolicy::new(Backoff::Fixed(Duration::from_secs(1))).max_retries(3))
+			.idempotency_key("order-42")
+			.forward_idempotency_key(true)
+			.build()
+			.secret_header(AUTHORIZATION, SecretRef::new("token"));
+		let serialized = request.to_payload().unwrap();
+		let deserialized = Request::from_payload(&serialized).unwrap();
 
 		assert_eq!(request.url, deserialized.url);
 		assert_eq!(request.method, deserialized.method);
 		assert_eq!(request.body, deserialized.body);
 		assert_eq!(request.headers, deserialized.headers);
+		assert_eq!(request.expires_at, deserialized.expires_at);
+		assert_eq!(request.timeout, deserialized.timeout);
+		assert_eq!(request.connect_timeout, deserialized.connect_timeout);
+		assert_eq!(request.retry_policy, deserialized.retry_policy);
+		assert_eq!(request.idempotency_key, deserialized.idempotency_key);
+		assert_eq!(request.forward_idempotency_key, deserialized.forward_idempotency_key);
+		assert_eq!(request.secret_headers, deserialized.secret_headers);
+	}
+
+	/// Checks that requests stored before payloads were versioned can still be
+	/// read
+	#[test]
+	fn legacy_payload() {
+		// A POST request to https://example.com/hook with a body of `{}`, an
+		// `X-Api-Key: secret` header and accepting 202, as serialized by 0.7.2
+		const LEGACY: &[u8] = &[
+			24, 0, 0, 0, 0, 0, 0, 0, 104, 116, 116, 112, 115, 58, 47, 47, 101, 120, 97, 109, 112,
+			108, 101, 46, 99, 111, 109, 47, 104, 111, 111, 107, 1, 2, 0, 0, 0, 0, 0, 0, 0, 123,
+			125, 4, 0, 0, 0, 0, 0, 0, 0, 80, 79, 83, 84, 1, 0, 0, 0, 0, 0, 0, 0, 9, 0, 0, 0, 0, 0,
+			0, 0, 120, 45, 97, 112, 105, 45, 107, 101, 121, 1, 0, 0, 0, 0, 0, 0, 0, 6, 0, 0, 0, 0,
+			0, 0, 0, 115, 101, 99, 114, 101, 116, 1, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 202, 0,
+		];
+		let request = Request::from_payload(LEGACY).unwrap();
+		assert_eq!(request.url.as_str(), "https://example.com/hook", "URL mismatch");
+		assert_eq!(request.method, Method::POST, "Method mismatch");
+		assert_eq!(request.body.as_deref(), Some(&b"{}"[..]), "Body mismatch");
+		assert_eq!(request.headers["x-api-key"], "secret", "Header mismatch");
+		assert!(request.accepts(StatusCode::ACCEPTED), "Accepted responses mismatch");
+		assert!(!request.accepts(StatusCode::OK), "Accepted responses mismatch");
+		assert!(request.expires_at.is_none() && request.retry_policy.is_none());
+
+		let mut future = request.to_payload().unwrap();
+		future[5] += 1;
+		assert!(Request::from_payload(&future).is_err(), "Unknown version was read");
 	}
 
 	#[test]
@@ -315,6 +583,21 @@ mod tests {
 		assert_eq!(request.body.unwrap(), b"body", "Body mismatch");
 	}
 
+	#[test]
+	fn expiry() {
+		let request = Request::get("https://foo.bar/").unwrap().build();
+		assert!(!request.is_expired(), "Request without expiry expired");
+
+		let request = request.expire_after(Duration::from_secs(60));
+		assert!(!request.is_expired(), "Request expired early");
+
+		let request = Request::get("https://foo.bar/")
+			.unwrap()
+			.expires_at(Utc::now() - chrono::Duration::seconds(1))
+			.build();
+		assert!(request.is_expired(), "Request didn't expire");
+	}
+
 	#[test]
 	fn test_url_parse_error() {
 		let parse_error = Request::delete("test.de").err().unwrap();
@@ -0,0 +1,175 @@
+//! Responses to returning jobs are stored in the database once received, so
+//! they can be collected by any process, even after the one which spawned the
+//! job has restarted.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use reqwest::{header::HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+	postgres::{PgListener, PgRow},
+	Executor, PgPool, Postgres, Row, Transaction,
+};
+use uuid::Uuid;
+
+use crate::{error::SpawnError, job::ResponseSender};
+
+/// The postgres notification channel the UUIDs of returning jobs are published
+/// on once they have finished.
+pub(crate) const NOTIFY_CHANNEL: &str = "requeuest_response";
+
+/// The response to a successfully delivered request.
+#[derive(Debug, Clone)]
+pub struct Response {
+	/// The UUID of the job which sent the request.
+	pub id: Uuid,
+	/// The status code of the response.
+	pub status: StatusCode,
+	/// The headers of the response.
+	pub headers: HeaderMap,
+	/// The body of the response.
+	pub body: Vec<u8>,
+	/// When the response was received.
+	pub completed_at: DateTime<Utc>,
+}
+
+/// Wrapper for (de)serializing response headers with bincode.
+#[derive(Serialize, Deserialize)]
+struct Headers(#[serde(with = "http_serde::header_map")] HeaderMap);
+
+impl Response {
+	/// Reads the full response to the request sent by the given job.
+	pub(crate) async fn read(
+		id: Uuid,
+		response: reqwest::Response,
+	) -> Result<Self, reqwest::Error> {
+		let status = response.status();
+		let headers = response.headers().clone();
+		let body = response.bytes().await?.to_vec();
+		Ok(Self { id, status, headers, body, completed_at: Utc::now() })
+	}
+
+	/// Constructs a response from a row of the response table.
+	fn from_row(row: &PgRow) -> Result<Self, SpawnError> {
+		let status: i32 = row.try_get("status")?;
+		let status = u16::try_from(status)
+			.ok()
+			.and_then(|status| StatusCode::from_u16(status).ok())
+			.ok_or_else(|| sqlx::Error::ColumnDecode {
+				index: "status".to_owned(),
+				source: format!("Invalid status code {}", status).into(),
+			})?;
+		let Headers(headers) = bincode::deserialize(row.try_get("headers")?)?;
+		Ok(Self {
+			id: row.try_get("id")?,
+			status,
+			headers,
+			body: row.try_get("body")?,
+			completed_at: row.try_get("completed_at")?,
+		})
+	}
+
+	/// Stores the response inside of the given transaction, notifying anyone
+	/// waiting for it once the transaction is committed.
+	pub(crate) async fn store(&self, tx: &mut Transaction<'_, Postgres>) -> Result<(), SpawnError> {
+		sqlx::query(
+			"INSERT INTO requeuest_responses (id, status, headers, body, completed_at)
+			VALUES ($1, $2, $3, $4, $5)",
+		)
+		.bind(self.id)
+		.bind(i32::from(self.status.as_u16()))
+		.bind(bincode::serialize(&Headers(self.headers.clone()))?)
+		.bind(&self.body)
+		.bind(self.completed_at)
+		.execute(&mut **tx)
+		.await?;
+		notify(&mut **tx, self.id).await?;
+		Ok(())
+	}
+}
+
+/// Notifies anyone waiting for the returning job with the given UUID that it
+/// has finished.
+pub(crate) async fn notify<'e, E>(executor: E, id: Uuid) -> Result<(), sqlx::Error>
+where
+	E: Executor<'e, Database = Postgres>,
+{
+	sqlx::query("SELECT pg_notify($1, $2)")
+		.bind(NOTIFY_CHANNEL)
+		.bind(id.to_string())
+		.execute(executor)
+		.await?;
+	Ok(())
+}
+
+/// Fetches the stored response to the job with the given UUID.
+pub(crate) async fn fetch(pool: &PgPool, id: Uuid) -> Result<Option<Response>, SpawnError> {
+	sqlx::query("SELECT * FROM requeuest_responses WHERE id = $1")
+		.bind(id)
+		.fetch_optional(pool)
+		.await?
+		.as_ref()
+		.map(Response::from_row)
+		.transpose()
+}
+
+/// Looks up the result of a returning job. Returns `None` if the job is still
+/// pending.
+pub(crate) async fn lookup(
+	pool: &PgPool,
+	id: Uuid,
+) -> Result<Option<Result<Response, SpawnError>>, SpawnError> {
+	// Jobs are removed from the queue in the same transaction their result is
+	// stored in, so the result of a job which isn't pending anymore is visible
+	// to the queries below.
+	let pending: bool = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM mq_msgs WHERE id = $1)")
+		.bind(id)
+		.fetch_one(pool)
+		.await?;
+	if let Some(response) = fetch(pool, id).await? {
+		return Ok(Some(Ok(response)));
+	}
+	if pending {
+		return Ok(None);
+	}
+
+	let (dead, cancelled): (bool, bool) = sqlx::query_as(
+		"SELECT
+			EXISTS(SELECT 1 FROM requeuest_dead_letters WHERE id = $1),
+			EXISTS(SELECT 1 FROM requeuest_outcomes WHERE id = $1 AND cancelled_at IS NOT NULL)",
+	)
+	.bind(id)
+	.fetch_one(pool)
+	.await?;
+	Ok(Some(Err(match (dead, cancelled) {
+		(true, _) => SpawnError::DeadLetter,
+		(false, true) => SpawnError::Cancelled,
+		(false, false) => SpawnError::Missing,
+	})))
+}
+
+/// Listens for notifications about finished returning jobs, and passes their
+/// results on to the tasks waiting for them.
+pub(crate) async fn listen(mut listener: PgListener, pool: PgPool, sender: ResponseSender) {
+	loop {
+		// `try_recv` returns `None` after the connection was lost and
+		// re-established, in which case notifications might have been missed.
+		let ids: Vec<Uuid> = match listener.try_recv().await {
+			Ok(Some(notification)) => notification.payload().parse().into_iter().collect(),
+			Ok(None) => sender.waiting(),
+			Err(_) => {
+				tokio::time::sleep(Duration::from_secs(1)).await;
+				continue;
+			}
+		};
+
+		for id in ids.into_iter().filter(|id| sender.is_waiting(*id)) {
+			match lookup(&pool, id).await {
+				Ok(Some(result)) => sender.send(id, result),
+				Ok(None) => {}
+				Err(error) => sender.send(id, Err(error)),
+			}
+		}
+	}
+}
@@ -0,0 +1,321 @@
+//! Retry policies control how long to wait between the attempts to deliver a
+//! request, and when to give up on it. Without a policy, sqlxmq doubles the
+//! wait after every attempt. Either way, the next attempt is pushed back to
+//! the time a rate limited or unavailable server asks to be retried at.
+
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Utc};
+use reqwest::{
+	header::{HeaderMap, RETRY_AFTER},
+	StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use sqlxmq::CurrentJob;
+
+use crate::request::Request;
+
+/// The longest wait a `Retry-After` header can ask for, regardless of any
+/// limits set.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// The longest delay before an attempt, which keeps the time of the next
+/// attempt well within the range of postgres timestamps.
+const MAX_DELAY: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+/// The longest wait a `Retry-After` header causes by default, for requests
+/// whose retry policy doesn't limit it.
+pub(crate) const DEFAULT_MAX_RETRY_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// How long to wait before the next attempt after a failed one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Backoff {
+	/// Wait `initial` after the first attempt, and double the wait after each
+	/// further attempt, up to at most `max`.
+	Exponential {
+		/// The wait after the first attempt.
+		initial: Duration,
+		/// The longest wait between two attempts.
+		max: Duration,
+	},
+	/// Wait `initial` after the first attempt, and add `step` to the wait
+	/// after each further attempt.
+	Linear {
+		/// The wait after the first attempt.
+		initial: Duration,
+		/// How much longer to wait after each further attempt.
+		step: Duration,
+	},
+	/// Wait the same amount of time after every attempt.
+	Fixed(Duration),
+	/// Wait a random amount of time between `base` and three times the
+	/// previous wait, up to at most `max`. Spreads out the retries of requests
+	/// which failed at the same time.
+	DecorrelatedJitter {
+		/// The shortest wait between two attempts.
+		base: Duration,
+		/// The longest wait between two attempts.
+		max: Duration,
+	},
+}
+
+impl Backoff {
+	/// Returns how long to wait after the given failed attempt, counting from
+	/// 1, where `previous` is how long was waited before it.
+	#[must_use]
+	pub fn delay(&self, attempt: u32, previous: Option<Duration>) -> Duration {
+		let retries = attempt.saturating_sub(1);
+		match *self {
+			Backoff::Exponential { initial, max } => {
+				initial.saturating_mul(2_u32.saturating_pow(retries)).min(max)
+			}
+			Backoff::Linear { initial, step } => {
+				initial.saturating_add(step.saturating_mul(retries))
+			}
+			Backoff::Fixed(delay) => delay,
+			Backoff::DecorrelatedJitter { base, max } => {
+				let upper = previous.unwrap_or(base).saturating_mul(3).max(base);
+				let millis = fastrand::u64(millis(base)..=millis(upper));
+				Duration::from_millis(millis).min(max)
+			}
+		}
+	}
+}
+
+/// Controls how long to wait between the attempts to deliver a request, and
+/// when to give up on it.
+///
+/// # Example
+/// ```
+/// use std::time::Duration;
+///
+/// use requeuest::retry::{Backoff, RetryPolicy};
+///
+/// let secs = Duration::from_secs;
+/// let backoff = Backoff::Exponential { initial: secs(1), max: secs(600) };
+/// let policy = RetryPolicy::new(backoff).max_retries(20);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[must_use]
+pub struct RetryPolicy {
+	/// How long to wait before the next attempt after a failed one.
+	pub backoff: Backoff,
+	/// How many times to retry the request after the first attempt. Defaults
+	/// to 100 000 if unset.
+	pub max_retries: Option<u32>,
+	/// How long after being spawned to give up on the request.
+	pub max_duration: Option<Duration>,
+	/// The longest wait a `Retry-After` header can cause. Longer waits asked
+	/// for by the server are cut short. Falls back to the client's default if
+	/// unset.
+	pub max_retry_after: Option<Duration>,
+}
+
+impl RetryPolicy {
+	/// Constructs a retry policy with the given backoff, which retries
+	/// indefinitely.
+	pub fn new(backoff: Backoff) -> Self {
+		Self { backoff, max_retries: None, max_duration: None, max_retry_after: None }
+	}
+
+	/// Sets how many times to retry the request after the first attempt.
+	pub fn max_retries(mut self, max_retries: u32) -> Self {
+		self.max_retries = Some(max_retries);
+		self
+	}
+
+	/// Sets how long after being spawned to give up on the request. No
+	/// attempt which would start later than that is made.
+	pub fn max_duration(mut self, max_duration: Duration) -> Self {
+		self.max_duration = Some(max_duration);
+		self
+	}
+
+	/// Sets the longest wait a `Retry-After` header can cause.
+	pub fn max_retry_after(mut self, max_retry_after: Duration) -> Self {
+		self.max_retry_after = Some(max_retry_after);
+		self
+	}
+
+	/// Returns whether an attempt starting at `attempt_at` is allowed for a
+	/// request spawned at `created_at`.
+	#[must_use]
+	pub fn allows(&self, created_at: DateTime<Utc>, attempt_at: DateTime<Utc>) -> bool {
+		let Some(max_duration) = self.max_duration else {
+			return true;
+		};
+		(attempt_at - created_at).to_std().map_or(true, |elapsed| elapsed <= max_duration)
+	}
+}
+
+/// The longest wait a `Retry-After` header causes for requests whose retry
+/// policy doesn't limit it, which the job runner is given as a context.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RetryAfterLimit(pub Duration);
+
+impl RetryAfterLimit {
+	/// Returns how long the server asks to wait before retrying the given
+	/// request, limited by its retry policy or this default.
+	pub fn retry_after(
+		self,
+		request: &Request,
+		status: StatusCode,
+		headers: &HeaderMap,
+	) -> Option<Duration> {
+		let max = request.retry_policy.and_then(|policy| policy.max_retry_after).unwrap_or(self.0);
+		retry_after(status, headers).map(|retry_after| retry_after.min(max))
+	}
+}
+
+/// Converts a duration to milliseconds, saturating on overflow.
+fn millis(duration: Duration) -> u64 {
+	u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+/// Converts a delay before an attempt to milliseconds, limited to the longest
+/// delay before an attempt.
+fn delay_millis(delay: Duration) -> i64 {
+	i64::try_from(millis(delay.min(MAX_DELAY))).unwrap_or(i64::MAX)
+}
+
+/// Returns how long the server asks to wait before retrying, if the response
+/// has a 429 or 503 status and a `Retry-After` header in either its
+/// delta-seconds or HTTP-date form. Waits longer than a week are cut short.
+pub(crate) fn retry_after(status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+	if status != StatusCode::TOO_MANY_REQUESTS && status != StatusCode::SERVICE_UNAVAILABLE {
+		return None;
+	}
+	let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+	let retry_after = match value.parse::<u64>() {
+		Ok(seconds) => Duration::from_secs(seconds),
+		Err(_) => {
+			let date = httpdate::parse_http_date(value).ok()?;
+			date.duration_since(SystemTime::now()).unwrap_or_default()
+		}
+	};
+	Some(retry_after.min(MAX_RETRY_AFTER))
+}
+
+/// Pushes the next attempt of the given job back to at least the given delay
+/// from now.
+pub(crate) async fn defer(job: &CurrentJob, delay: Duration) -> Result<(), sqlx::Error> {
+	let delay = delay_millis(delay);
+	sqlx::query(
+		"UPDATE mq_msgs SET attempt_at = GREATEST(attempt_at, NOW() + $2 * INTERVAL '1 millisecond')
+		WHERE id = $1 AND attempts > 0",
+	)
+	.bind(job.id())
+	.bind(delay)
+	.execute(job.pool())
+	.await?;
+	Ok(())
+}
+
+/// Schedules the next attempt of the given job after the given delay.
+pub(crate) async fn reschedule(job: &CurrentJob, delay: Duration) -> Result<(), sqlx::Error> {
+	let delay = delay_millis(delay);
+	let mut tx = job.pool().begin().await?;
+	sqlx::query(
+		"UPDATE mq_msgs SET attempt_at = NOW() + $2 * INTERVAL '1 millisecond'
+		WHERE id = $1 AND attempts > 0",
+	)
+	.bind(job.id())
+	.bind(delay)
+	.execute(&mut *tx)
+	.await?;
+	sqlx::query("UPDATE requeuest_outcomes SET retry_delay_ms = $2 WHERE id = $1")
+		.bind(job.id())
+		.bind(delay)
+		.execute(&mut *tx)
+		.await?;
+	tx.commit().await
+}
+
+#[cfg(test)]
+mod tests {
+	#![allow(clippy::unwrap_used)]
+	use std::time::{Duration, SystemTime};
+
+	use chrono::Utc;
+	use reqwest::{
+		header::{HeaderMap, HeaderValue, RETRY_AFTER},
+		StatusCode,
+	};
+
+	use super::{retry_after, Backoff, RetryAfterLimit, RetryPolicy, MAX_RETRY_AFTER};
+	use crate::request::Request;
+
+	#[test]
+	fn backoff() {
+		let secs = Duration::from_secs;
+		let exponential = Backoff::Exponential { initial: secs(1), max: secs(5) };
+		assert_eq!(exponential.delay(1, None), secs(1));
+		assert_eq!(exponential.delay(3, Some(secs(2))), secs(4));
+		assert_eq!(exponential.delay(4, Some(secs(4))), secs(5));
+		assert_eq!(exponential.delay(u32::MAX, None), secs(5));
+
+		let linear = Backoff::Linear { initial: secs(1), step: secs(2) };
+		assert_eq!(linear.delay(1, None), secs(1));
+		assert_eq!(linear.delay(3, Some(secs(3))), secs(5));
+
+		assert_eq!(Backoff::Fixed(secs(3)).delay(10, Some(secs(3))), secs(3));
+
+		let jitter = Backoff::DecorrelatedJitter { base: secs(1), max: secs(10) };
+		for _ in 0..100 {
+			let delay = jitter.delay(2, Some(secs(2)));
+			assert!(delay >= secs(1) && delay <= secs(6), "Jittered delay out of range");
+			assert!(jitter.delay(5, Some(secs(60))) <= secs(10), "Jittered delay above max");
+		}
+	}
+
+	#[test]
+	fn max_duration() {
+		let policy = RetryPolicy::new(Backoff::Fixed(Duration::from_secs(1)));
+		let now = Utc::now();
+		assert!(policy.allows(now, now + chrono::Duration::days(365)));
+
+		let policy = policy.max_duration(Duration::from_secs(60));
+		assert!(policy.allows(now, now + chrono::Duration::seconds(60)));
+		assert!(!policy.allows(now, now + chrono::Duration::seconds(61)));
+		assert!(policy.allows(now, now - chrono::Duration::seconds(1)));
+	}
+
+	#[test]
+	fn retry_after_header() {
+		let headers = |value: &str| {
+			let mut headers = HeaderMap::new();
+			headers.insert(RETRY_AFTER, HeaderValue::from_str(value).unwrap());
+			headers
+		};
+		let limited = StatusCode::TOO_MANY_REQUESTS;
+		assert_eq!(retry_after(limited, &headers("120")), Some(Duration::from_secs(120)));
+		assert_eq!(
+			retry_after(StatusCode::SERVICE_UNAVAILABLE, &headers(" 5 ")),
+			Some(Duration::from_secs(5))
+		);
+		assert_eq!(retry_after(StatusCode::BAD_GATEWAY, &headers("120")), None);
+		assert_eq!(retry_after(limited, &HeaderMap::new()), None);
+		assert_eq!(retry_after(limited, &headers("soon")), None);
+
+		let past = headers("Sun, 06 Nov 1994 08:49:37 GMT");
+		assert_eq!(retry_after(limited, &past), Some(Duration::ZERO));
+		let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(3600));
+		let delay = retry_after(limited, &headers(&date)).unwrap();
+		assert!(delay > Duration::from_secs(3590) && delay <= Duration::from_secs(3600));
+
+		let absurd = headers("18446744073709551615");
+		assert_eq!(retry_after(limited, &absurd), Some(MAX_RETRY_AFTER));
+		let absurd_date = headers("Fri, 31 Dec 9999 23:59:59 GMT");
+		assert_eq!(retry_after(limited, &absurd_date), Some(MAX_RETRY_AFTER));
+
+		let limit = RetryAfterLimit(Duration::from_secs(60));
+		let request = Request::get("https://example.com/").unwrap().build();
+		assert_eq!(limit.retry_after(&request, limited, &absurd), Some(Duration::from_secs(60)));
+		let policy = RetryPolicy::new(Backoff::Fixed(Duration::from_secs(1)))
+			.max_retry_after(Duration::from_secs(600));
+		let request = Request::get("https://example.com/").unwrap().retry_policy(policy).build();
+		assert_eq!(limit.retry_after(&request, limited, &absurd), Some(Duration::from_secs(600)));
+		assert_eq!(
+			limit.retry_after(&request, limited, &headers("5")),
+			Some(Duration::from_secs(5))
+		);
+	}
+}
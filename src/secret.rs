@@ -0,0 +1,108 @@
+//! Secret headers keep credentials out of the queue. Instead of its value, a
+//! request stores a [`SecretRef`] naming the secret, see
+//! [`Request::secret_header`], and the job runner asks the
+//! [`SecretResolver`] registered with
+//! [`ClientBuilder::secret_resolver`](crate::client::ClientBuilder::secret_resolver)
+//! for the value at each attempt. Rotated credentials thereby apply to requests
+//! which are already queued.
+
+use std::{collections::HashMap, fmt, future::Future, hash::BuildHasher, pin::Pin, sync::Arc};
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::SecretError, request::Request};
+
+/// A boxed error of a secret resolver.
+pub type ResolverError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// The future a secret resolver returns, which resolves to the value of the
+/// secret, or `None` if there is no secret of that name.
+pub type Resolution<'a> =
+	Pin<Box<dyn Future<Output = Result<Option<HeaderValue>, ResolverError>> + Send + 'a>>;
+
+/// A reference to a secret by name, which is stored with the request in place
+/// of its value.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct SecretRef(pub String);
+
+impl SecretRef {
+	/// Constructs a reference to the secret with the given name.
+	pub fn new(name: impl Into<String>) -> Self {
+		Self(name.into())
+	}
+}
+
+/// Looks up the values of secrets by name, e.g. from a vault or the
+/// environment. It's called at each attempt to send a request with secret
+/// headers, so it should cache values which are expensive to look up.
+///
+/// A map of names to values resolves to the values it holds.
+pub trait SecretResolver: Send + Sync + 'static {
+	/// Resolves the value of the secret with the given name, returning `None`
+	/// if there is no such secret.
+	fn resolve<'a>(&'a self, name: &'a str) -> Resolution<'a>;
+}
+
+impl<S> SecretResolver for HashMap<String, HeaderValue, S>
+where
+	S: BuildHasher + Send + Sync + 'static,
+{
+	fn resolve<'a>(&'a self, name: &'a str) -> Resolution<'a> {
+		let value = self.get(name).cloned();
+		Box::pin(async move { Ok(value) })
+	}
+}
+
+/// Resolves the secret headers of the requests of a job runner with the
+/// resolver registered on its client, if there is one.
+#[derive(Clone, Default)]
+pub(crate) struct Secrets(Option<Arc<dyn SecretResolver>>);
+
+impl fmt::Debug for Secrets {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_tuple("Secrets").field(&self.0.is_some()).finish()
+	}
+}
+
+impl Secrets {
+	/// Constructs secrets resolved by the given resolver.
+	pub fn new(resolver: impl SecretResolver) -> Self {
+		Self(Some(Arc::new(resolver)))
+	}
+
+	/// Checks that the secret headers of the given request have valid names,
+	/// and that there's a resolver to resolve them with if it has any. Whether
+	/// the resolver knows the secrets is only found out when resolving them.
+	pub fn check(&self, request: &Request) -> Result<(), SecretError> {
+		for (name, secret) in &request.secret_headers {
+			HeaderName::from_bytes(name.as_bytes()).map_err(SecretError::InvalidName)?;
+			if self.0.is_none() {
+				return Err(SecretError::Unknown(secret.0.clone()));
+			}
+		}
+		Ok(())
+	}
+
+	/// Resolves the secret headers of the given request, and inserts their
+	/// values into the given headers, marked as sensitive.
+	pub async fn resolve(
+		&self,
+		request: &Request,
+		headers: &mut HeaderMap,
+	) -> Result<(), SecretError> {
+		for (name, secret) in &request.secret_headers {
+			let name = HeaderName::from_bytes(name.as_bytes()).map_err(SecretError::InvalidName)?;
+			let unknown = || SecretError::Unknown(secret.0.clone());
+			let resolver = self.0.as_ref().ok_or_else(unknown)?;
+			let mut value = resolver
+				.resolve(&secret.0)
+				.await
+				.map_err(SecretError::Resolver)?
+				.ok_or_else(unknown)?;
+			value.set_sensitive(true);
+			headers.insert(name, value);
+		}
+		Ok(())
+	}
+}
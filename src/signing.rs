@@ -0,0 +1,309 @@
+//! Request signing lets the receiving end verify that a request was sent by
+//! the application, e.g. for webhooks. Signing keys are registered on the
+//! client by name with
+//! [`ClientBuilder::signing_key`](crate::client::ClientBuilder::signing_key),
+//! and requests refer to one with [`Request::signing_key`]. The job runner
+//! signs the request at each attempt, so its timestamp doesn't go stale across
+//! retries, and the secret is never stored in the queue.
+
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::{error::SigningError, request::Request};
+
+/// A boxed error of a signing scheme.
+pub type SchemeError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// What a request is signed over at an attempt.
+#[derive(Debug, Clone, Copy)]
+pub struct Message<'a> {
+	/// The UUID of the job sending the request, which stays the same across
+	/// attempts.
+	pub id: Uuid,
+	/// When the attempt was started.
+	pub timestamp: DateTime<Utc>,
+	/// The request being sent.
+	pub request: &'a Request,
+	/// The body of the request, which is empty if it has none.
+	pub body: &'a [u8],
+}
+
+/// A way of signing requests, which adds the headers carrying the signature.
+/// Implement it for signing schemes other than the built-in ones.
+pub trait Scheme: Send + Sync + 'static {
+	/// Signs the given message with the given secret, and inserts the
+	/// resulting headers into the headers of the request.
+	fn sign(
+		&self,
+		secret: &[u8],
+		message: &Message<'_>,
+		headers: &mut HeaderMap,
+	) -> Result<(), SchemeError>;
+}
+
+/// Computes the HMAC-SHA256 of the concatenation of the given parts with the
+/// given secret, for implementing signing schemes.
+pub fn hmac_sha256(secret: &[u8], parts: &[&[u8]]) -> Result<[u8; 32], SchemeError> {
+	let mut mac = Hmac::<Sha256>::new_from_slice(secret)?;
+	for part in parts {
+		mac.update(part);
+	}
+	Ok(mac.finalize().into_bytes().into())
+}
+
+/// The [Standard Webhooks](https://www.standardwebhooks.com/) scheme. Sends
+/// the UUID of the job in a `webhook-id` header, the Unix timestamp in a
+/// `webhook-timestamp` header, and a `webhook-signature` header with the base64
+/// encoded HMAC-SHA256 of `{id}.{timestamp}.{body}`, prefixed with `v1,`.
+///
+/// Standard Webhooks secrets are usually given base64 encoded with a `whsec_`
+/// prefix, which have to be decoded before registering them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StandardWebhooks;
+
+impl Scheme for StandardWebhooks {
+	fn sign(
+		&self,
+		secret: &[u8],
+		message: &Message<'_>,
+		headers: &mut HeaderMap,
+	) -> Result<(), SchemeError> {
+		let id = message.id.to_string();
+		let timestamp = message.timestamp.timestamp().to_string();
+		let signature =
+			hmac_sha256(secret, &[id.as_bytes(), b".", timestamp.as_bytes(), b".", message.body])?;
+		headers.insert("webhook-id", HeaderValue::from_str(&id)?);
+		headers.insert("webhook-timestamp", HeaderValue::from_str(&timestamp)?);
+		let signature = format!("v1,{}", BASE64.encode(signature));
+		headers.insert("webhook-signature", HeaderValue::from_str(&signature)?);
+		Ok(())
+	}
+}
+
+/// The scheme of Stripe's webhooks. Sends a header, `Stripe-Signature` by
+/// default, of the form `t={timestamp},v1={signature}`, where the signature is
+/// the hex encoded HMAC-SHA256 of `{timestamp}.{body}`.
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct Stripe {
+	/// The header the signature is sent in.
+	pub header: HeaderName,
+}
+
+impl Stripe {
+	/// Constructs the scheme, sending the signature in a `Stripe-Signature`
+	/// header.
+	pub fn new() -> Self {
+		Self { header: HeaderName::from_static("stripe-signature") }
+	}
+
+	/// Sets the header the signature is sent in.
+	pub fn header(mut self, header: HeaderName) -> Self {
+		self.header = header;
+		self
+	}
+}
+
+impl Default for Stripe {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Scheme for Stripe {
+	fn sign(
+		&self,
+		secret: &[u8],
+		message: &Message<'_>,
+		headers: &mut HeaderMap,
+	) -> Result<(), SchemeError> {
+		let timestamp = message.timestamp.timestamp().to_string();
+		let signature = hmac_sha256(secret, &[timestamp.as_bytes(), b".", message.body])?;
+		let value = format!("t={timestamp},v1={}", hex::encode(signature));
+		headers.insert(self.header.clone(), HeaderValue::from_str(&value)?);
+		Ok(())
+	}
+}
+
+/// A scheme sending the Unix timestamp in a header, `X-Timestamp` by default,
+/// and the hex encoded HMAC-SHA256 of `{timestamp}.{body}`, prefixed with
+/// `sha256=`, in another one, `X-Signature` by default.
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct Timestamped {
+	/// The header the signature is sent in.
+	pub signature_header: HeaderName,
+	/// The header the timestamp is sent in.
+	pub timestamp_header: HeaderName,
+}
+
+impl Timestamped {
+	/// Constructs the scheme, sending the signature in an `X-Signature` header
+	/// and the timestamp in an `X-Timestamp` header.
+	pub fn new() -> Self {
+		Self {
+			signature_header: HeaderName::from_static("x-signature"),
+			timestamp_header: HeaderName::from_static("x-timestamp"),
+		}
+	}
+
+	/// Sets the header the signature is sent in.
+	pub fn signature_header(mut self, header: HeaderName) -> Self {
+		self.signature_header = header;
+		self
+	}
+
+	/// Sets the header the timestamp is sent in.
+	pub fn timestamp_header(mut self, header: HeaderName) -> Self {
+		self.timestamp_header = header;
+		self
+	}
+}
+
+impl Default for Timestamped {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Scheme for Timestamped {
+	fn sign(
+		&self,
+		secret: &[u8],
+		message: &Message<'_>,
+		headers: &mut HeaderMap,
+	) -> Result<(), SchemeError> {
+		let timestamp = message.timestamp.timestamp().to_string();
+		let signature = hmac_sha256(secret, &[timestamp.as_bytes(), b".", message.body])?;
+		let signature = format!("sha256={}", hex::encode(signature));
+		headers.insert(self.timestamp_header.clone(), HeaderValue::from_str(&timestamp)?);
+		headers.insert(self.signature_header.clone(), HeaderValue::from_str(&signature)?);
+		Ok(())
+	}
+}
+
+/// A secret requests are signed with, along with the scheme they're signed
+/// in.
+///
+/// # Example
+/// ```
+/// use requeuest::signing::{SigningKey, Stripe};
+///
+/// let key = SigningKey::new(b"whsec_secret".to_vec(), Stripe::new());
+/// ```
+#[derive(Clone)]
+pub struct SigningKey {
+	/// The secret requests are signed with.
+	secret: Arc<[u8]>,
+	/// The scheme requests are signed in.
+	scheme: Arc<dyn Scheme>,
+}
+
+impl SigningKey {
+	/// Constructs a key which signs requests with the given secret in the
+	/// given scheme.
+	pub fn new(secret: impl Into<Vec<u8>>, scheme: impl Scheme) -> Self {
+		Self { secret: secret.into().into(), scheme: Arc::new(scheme) }
+	}
+}
+
+impl fmt::Debug for SigningKey {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("SigningKey").finish_non_exhaustive()
+	}
+}
+
+/// Signs the requests of a job runner with the keys registered on its client.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Signer(Arc<HashMap<String, SigningKey>>);
+
+impl Signer {
+	/// Constructs a signer with the given keys.
+	pub fn new(keys: HashMap<String, SigningKey>) -> Self {
+		Self(Arc::new(keys))
+	}
+
+	/// Returns the key the given request refers to, if it refers to one.
+	fn key(&self, request: &Request) -> Result<Option<&SigningKey>, SigningError> {
+		let Some(name) = &request.signing_key else {
+			return Ok(None);
+		};
+		self.0.get(name).map(Some).ok_or_else(|| SigningError::UnknownKey(name.clone()))
+	}
+
+	/// Checks that the key the given request refers to is registered, if it
+	/// refers to one.
+	pub fn check(&self, request: &Request) -> Result<(), SigningError> {
+		self.key(request).map(|_| ())
+	}
+
+	/// Signs the given request of the job with the given UUID with the key it
+	/// refers to, if it refers to one, inserting the signature into the given
+	/// headers.
+	pub fn sign(
+		&self,
+		id: Uuid,
+		request: &Request,
+		headers: &mut HeaderMap,
+	) -> Result<(), SigningError> {
+		let Some(key) = self.key(request)? else {
+			return Ok(());
+		};
+		let message = Message {
+			id,
+			timestamp: Utc::now(),
+			request,
+			body: request.body.as_deref().unwrap_or_default(),
+		};
+		key.scheme.sign(&key.secret, &message, headers).map_err(SigningError::Scheme)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	#![allow(clippy::unwrap_used)]
+	use chrono::{TimeZone, Utc};
+	use reqwest::header::HeaderMap;
+	use uuid::Uuid;
+
+	use super::{Message, Scheme, StandardWebhooks, Stripe, Timestamped};
+	use crate::request::Request;
+
+	#[test]
+	fn schemes() {
+		let request = Request::post("https://example.com/", b"{}".to_vec()).unwrap().build();
+		let message = Message {
+			id: Uuid::nil(),
+			timestamp: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+			request: &request,
+			body: b"{}",
+		};
+
+		let mut headers = HeaderMap::new();
+		StandardWebhooks.sign(b"secret", &message, &mut headers).unwrap();
+		assert_eq!(headers["webhook-id"], "00000000-0000-0000-0000-000000000000");
+		assert_eq!(headers["webhook-timestamp"], "1700000000");
+		assert_eq!(headers["webhook-signature"], "v1,UMqTzVStTlGTBM6aff5+h5Egrr1H0WpUBWsGoS7FUWs=");
+
+		let mut headers = HeaderMap::new();
+		Stripe::new().sign(b"secret", &message, &mut headers).unwrap();
+		assert_eq!(
+			headers["stripe-signature"],
+			"t=1700000000,v1=b8569b78799ff9e3cbff0fc2d63a33a2b57f3282abd07c37ae5e8e7d79a5f163"
+		);
+
+		let mut headers = HeaderMap::new();
+		Timestamped::new().sign(b"secret", &message, &mut headers).unwrap();
+		assert_eq!(headers["x-timestamp"], "1700000000");
+		assert_eq!(
+			headers["x-signature"],
+			"sha256=b8569b78799ff9e3cbff0fc2d63a33a2b57f3282abd07c37ae5e8e7d79a5f163"
+		);
+	}
+}
@@ -0,0 +1,442 @@
+//! The outcome of each job's latest delivery attempt is recorded, which along
+//! with the state of the queue lets the [`Client`](crate::Client) report what
+//! happened to a spawned request.
+
+use std::{collections::HashMap, time::Duration};
+
+use chrono::{DateTime, Utc};
+use reqwest::StatusCode;
+use sqlx::{postgres::PgRow, Executor, PgPool, Postgres, Row};
+use sqlxmq::CurrentJob;
+use uuid::Uuid;
+
+use crate::{
+	callback::{FinalState, Report},
+	encryption::Cipher,
+	response,
+};
+
+/// The outcome of a single delivery attempt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+	/// A response with the given status code was received.
+	Response(StatusCode),
+	/// No response was received due to the given error.
+	Error(String),
+}
+
+impl Outcome {
+	/// Constructs an outcome from the status and error columns it's stored in.
+	pub(crate) fn from_columns(status: Option<i32>, error: Option<String>) -> Option<Self> {
+		let status = status
+			.and_then(|status| u16::try_from(status).ok())
+			.and_then(|status| StatusCode::from_u16(status).ok());
+		match (status, error) {
+			(Some(status), _) => Some(Outcome::Response(status)),
+			(None, Some(error)) => Some(Outcome::Error(error)),
+			(None, None) => None,
+		}
+	}
+
+	/// Returns the values of the status and error columns the outcome is
+	/// stored in.
+	pub(crate) fn columns(&self) -> (Option<i32>, Option<&str>) {
+		match self {
+			Outcome::Response(status) => (Some(i32::from(status.as_u16())), None),
+			Outcome::Error(error) => (None, Some(error.as_str())),
+		}
+	}
+}
+
+/// The state a job is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+	/// The job is waiting for a job runner to pick it up.
+	Pending,
+	/// The job is waiting until the given time before its next attempt.
+	Scheduled(DateTime<Utc>),
+	/// An attempt to deliver the request is currently being made.
+	InFlight,
+	/// The request was delivered at the given time.
+	Completed(DateTime<Utc>),
+	/// The request ran out of attempts at the given time, and was moved to
+	/// the dead letter table.
+	Dead(DateTime<Utc>),
+	/// The job was cancelled at the given time before its request was
+	/// delivered.
+	Cancelled(DateTime<Utc>),
+	/// The job was removed from the queue without the request being
+	/// delivered, for example because it expired or its channel was cleared.
+	Abandoned,
+}
+
+/// The state a job was in when it got cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cancellation {
+	/// The job was waiting in the queue, and won't be attempted.
+	Pending,
+	/// An attempt was being made when the job got cancelled. That attempt may
+	/// still deliver the request, but no further attempts will be made.
+	InFlight,
+	/// The job wasn't in the queue, because it had already finished or never
+	/// existed.
+	Gone,
+}
+
+/// What has happened to a spawned job so far.
+#[derive(Debug, Clone)]
+pub struct JobStatus {
+	/// The UUID of the job.
+	pub id: Uuid,
+	/// The channel the job was spawned on.
+	pub channel: String,
+	/// The state the job is in.
+	pub state: JobState,
+	/// When the job was spawned.
+	pub created_at: DateTime<Utc>,
+	/// The number of attempts the job has left, including the next one.
+	pub attempts_remaining: u32,
+	/// When the next attempt will be made, if the job is still queued.
+	pub next_attempt_at: Option<DateTime<Utc>>,
+	/// The number of attempts which have been started.
+	pub attempts: u32,
+	/// When the last attempt was started.
+	pub last_attempt_at: Option<DateTime<Utc>>,
+	/// The outcome of the last finished attempt.
+	pub last_outcome: Option<Outcome>,
+}
+
+/// How many jobs are queued on a channel, by the state they're in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueStats {
+	/// The channel the jobs were spawned on.
+	pub channel: String,
+	/// The number of jobs waiting for a job runner to pick them up.
+	pub pending: u64,
+	/// The number of ordered jobs waiting for the job spawned before them to
+	/// leave the queue, which job runners don't pick up until then.
+	pub blocked: u64,
+	/// The number of jobs waiting until the time of their next attempt.
+	pub delayed: u64,
+	/// The number of jobs an attempt is currently being made for.
+	pub in_flight: u64,
+	/// How long ago the oldest job still in the queue was spawned.
+	pub oldest_age: Duration,
+}
+
+/// The attempt to deliver a request which is being made.
+#[derive(Debug, Clone)]
+pub(crate) struct Attempt {
+	/// The number of the attempt, counting from 1.
+	pub number: u32,
+	/// The channel the job was spawned on.
+	pub channel: String,
+	/// When the job was spawned.
+	pub created_at: DateTime<Utc>,
+	/// How long was waited before the attempt, if a retry policy set it.
+	pub retry_delay: Option<Duration>,
+	/// Whether the job has no attempts left after this one.
+	pub last: bool,
+}
+
+/// Records that an attempt to deliver the request of the given job has
+/// started. Returns `None` if the job is no longer queued, as it was cancelled
+/// after being picked up.
+pub(crate) async fn attempt_started(job: &CurrentJob) -> Result<Option<Attempt>, sqlx::Error> {
+	let row = sqlx::query(
+		"WITH queued AS (
+			SELECT id, channel_name, created_at, attempts FROM mq_msgs WHERE id = $1
+		), started AS (
+			INSERT INTO requeuest_outcomes
+				(id, channel_name, attempts, in_flight, last_attempt_at, created_at)
+			SELECT id, channel_name, 1, TRUE, NOW(), COALESCE(created_at, NOW())
+			FROM queued
+			ON CONFLICT (id) DO UPDATE SET
+				attempts = requeuest_outcomes.attempts + 1,
+				in_flight = TRUE,
+				last_attempt_at = NOW()
+			RETURNING id, attempts, channel_name, created_at, retry_delay_ms
+		)
+		SELECT
+			started.attempts,
+			started.channel_name,
+			started.created_at,
+			started.retry_delay_ms,
+			queued.attempts = 0 AS last
+		FROM started
+		INNER JOIN queued ON queued.id = started.id",
+	)
+	.bind(job.id())
+	.fetch_optional(job.pool())
+	.await?;
+
+	let Some(row) = row else {
+		return Ok(None);
+	};
+	let number: i32 = row.try_get("attempts")?;
+	let retry_delay: Option<i64> = row.try_get("retry_delay_ms")?;
+	Ok(Some(Attempt {
+		number: u32::try_from(number).unwrap_or(1),
+		channel: row.try_get("channel_name")?,
+		created_at: row.try_get("created_at")?,
+		retry_delay: retry_delay
+			.and_then(|delay| u64::try_from(delay).ok())
+			.map(Duration::from_millis),
+		last: row.try_get("last")?,
+	}))
+}
+
+/// Records the outcome of the current attempt to deliver the request of the
+/// job with the given UUID, and whether the request was delivered by it.
+/// Returns whether the job was cancelled during the attempt.
+pub(crate) async fn record<'e, E>(
+	executor: E,
+	id: Uuid,
+	outcome: &Outcome,
+	completed: bool,
+) -> Result<bool, sqlx::Error>
+where
+	E: Executor<'e, Database = Postgres>,
+{
+	let (status, error) = outcome.columns();
+	let cancelled = sqlx::query_scalar(
+		"UPDATE requeuest_outcomes SET
+			in_flight = FALSE,
+			last_status = $2,
+			last_error = $3,
+			completed_at = CASE WHEN $4 THEN NOW() END
+		WHERE id = $1
+		RETURNING cancelled_at IS NOT NULL",
+	)
+	.bind(id)
+	.bind(status)
+	.bind(error)
+	.bind(completed)
+	.fetch_optional(executor)
+	.await?;
+	Ok(cancelled.unwrap_or(false))
+}
+
+/// Fetches the status of the job with the given UUID. Returns `None` if no
+/// job with the UUID is known.
+pub(crate) async fn fetch(pool: &PgPool, id: Uuid) -> Result<Option<JobStatus>, sqlx::Error> {
+	// Jobs are removed from the queue in the same transaction their final
+	// outcome is recorded in, so querying the queue first ensures the outcome
+	// of a job which has left it is visible to the queries below.
+	let queued = sqlx::query(
+		"SELECT channel_name, created_at, attempts, attempt_at FROM mq_msgs WHERE id = $1",
+	)
+	.bind(id)
+	.fetch_optional(pool)
+	.await?;
+	let outcome = sqlx::query("SELECT * FROM requeuest_outcomes WHERE id = $1")
+		.bind(id)
+		.fetch_optional(pool)
+		.await?;
+	let dead = sqlx::query(
+		"SELECT channel_name, created_at, failed_at FROM requeuest_dead_letters WHERE id = $1",
+	)
+	.bind(id)
+	.fetch_optional(pool)
+	.await?;
+
+	let (attempts, in_flight, last_attempt_at, last_outcome, completed_at) = match &outcome {
+		Some(row) => {
+			let attempts: i32 = row.try_get("attempts")?;
+			(
+				u32::try_from(attempts).unwrap_or_default(),
+				row.try_get("in_flight")?,
+				row.try_get("last_attempt_at")?,
+				Outcome::from_columns(row.try_get("last_status")?, row.try_get("last_error")?),
+				row.try_get("completed_at")?,
+			)
+		}
+		None => (0, false, None, None, None),
+	};
+
+	let (row, state, attempts_remaining, next_attempt_at) = if let Some(row) = queued {
+		let remaining: i32 = row.try_get("attempts")?;
+		let attempt_at: Option<DateTime<Utc>> = row.try_get("attempt_at")?;
+		// Job runners push the time of the next attempt forward when picking a
+		// job up, and the final attempt leaves no next attempt behind.
+		let state = match attempt_at {
+			None => JobState::InFlight,
+			Some(attempt_at) if attempt_at > Utc::now() && in_flight => JobState::InFlight,
+			Some(attempt_at) if attempt_at > Utc::now() => JobState::Scheduled(attempt_at),
+			Some(_) => JobState::Pending,
+		};
+		(row, state, u32::try_from(remaining).unwrap_or_default(), attempt_at)
+	} else if let Some(row) = dead {
+		let state = JobState::Dead(row.try_get("failed_at")?);
+		(row, state, 0, None)
+	} else if let Some(row) = outcome {
+		let cancelled_at: Option<DateTime<Utc>> = row.try_get("cancelled_at")?;
+		let state = match (completed_at, cancelled_at) {
+			(Some(completed_at), _) => JobState::Completed(completed_at),
+			(None, Some(cancelled_at)) => JobState::Cancelled(cancelled_at),
+			(None, None) => JobState::Abandoned,
+		};
+		(row, state, 0, None)
+	} else {
+		return Ok(None);
+	};
+
+	Ok(Some(JobStatus {
+		id,
+		channel: row.try_get("channel_name")?,
+		state,
+		created_at: row.try_get::<Option<_>, _>("created_at")?.unwrap_or_else(Utc::now),
+		attempts_remaining,
+		next_attempt_at,
+		attempts,
+		last_attempt_at,
+		last_outcome,
+	}))
+}
+
+/// Removes the jobs with the given UUIDs from the queue, returning the state
+/// each job was in, in the same order as the given UUIDs. Reports the
+/// cancellation to the callbacks of the requests, which are opened with the
+/// given cipher.
+pub(crate) async fn cancel(
+	pool: &PgPool,
+	ids: &[Uuid],
+	cipher: &Cipher,
+) -> Result<Vec<Cancellation>, sqlx::Error> {
+	let mut tx = pool.begin().await?;
+
+	// Job runners push the time of the next attempt forward when picking a job
+	// up, so a job with an unfinished attempt whose next attempt lies in the
+	// future is currently being attempted.
+	let rows = sqlx::query(
+		"SELECT
+			mq_msgs.id,
+			mq_msgs.channel_name,
+			mq_msgs.attempt_at IS NULL
+				OR (mq_msgs.attempt_at > NOW() AND COALESCE(requeuest_outcomes.in_flight, FALSE))
+				AS in_flight,
+			mq_payloads.payload_bytes,
+			COALESCE(requeuest_outcomes.attempts, 0) AS attempts,
+			requeuest_outcomes.last_status,
+			requeuest_outcomes.last_error
+		FROM mq_msgs
+		LEFT JOIN requeuest_outcomes ON requeuest_outcomes.id = mq_msgs.id
+		LEFT JOIN mq_payloads ON mq_payloads.id = mq_msgs.id
+		WHERE mq_msgs.id = ANY($1)
+		FOR UPDATE OF mq_msgs",
+	)
+	.bind(ids)
+	.fetch_all(&mut *tx)
+	.await?;
+
+	let mut queued = HashMap::new();
+	let mut followups = Vec::new();
+	for row in &rows {
+		let id: Uuid = row.try_get("id")?;
+		queued.insert(id, row.try_get::<bool, _>("in_flight")?);
+		// The callback of a request which can't be opened is unknown, so its
+		// cancellation can't be reported
+		let payload: Option<&[u8]> = row.try_get("payload_bytes")?;
+		let Some(request) = payload.and_then(|payload| cipher.decode(payload).ok()) else {
+			continue;
+		};
+		let Some(callback) = &request.callback else {
+			continue;
+		};
+		let channel: String = row.try_get("channel_name")?;
+		let attempts: i32 = row.try_get("attempts")?;
+		let outcome =
+			Outcome::from_columns(row.try_get("last_status")?, row.try_get("last_error")?);
+		let report = Report::new(
+			id,
+			&channel,
+			FinalState::Cancelled,
+			outcome.as_ref(),
+			None,
+			u32::try_from(attempts).unwrap_or_default(),
+		);
+		followups.push(report.request(callback, cipher).map_err(sqlx::Error::Encode)?);
+	}
+
+	let cancelled: Vec<Uuid> = queued.keys().copied().collect();
+	sqlx::query(
+		"INSERT INTO requeuest_outcomes (id, channel_name, cancelled_at, created_at)
+		SELECT id, channel_name, NOW(), COALESCE(created_at, NOW())
+		FROM mq_msgs
+		WHERE id = ANY($1)
+		ON CONFLICT (id) DO UPDATE SET cancelled_at = NOW()",
+	)
+	.bind(&cancelled)
+	.execute(&mut *tx)
+	.await?;
+	sqlx::query("SELECT mq_delete($1)").bind(&cancelled).execute(&mut *tx).await?;
+	for id in &cancelled {
+		response::notify(&mut *tx, *id).await?;
+	}
+	for followup in &followups {
+		followup.spawn(&mut *tx).await?;
+	}
+	tx.commit().await?;
+
+	Ok(ids
+		.iter()
+		.map(|id| match queued.get(id) {
+			Some(false) => Cancellation::Pending,
+			Some(true) => Cancellation::InFlight,
+			None => Cancellation::Gone,
+		})
+		.collect())
+}
+
+/// Counts the jobs queued on each channel by the state they're in, using the
+/// same classification as [`fetch`], except that jobs blocked by the job
+/// they're ordered after are counted separately, like `mq_poll` tells them
+/// apart.
+pub(crate) async fn queue_stats(pool: &PgPool) -> Result<Vec<QueueStats>, sqlx::Error> {
+	let rows = sqlx::query(
+		"SELECT
+			channel_name,
+			COUNT(*) FILTER (WHERE attempt_at <= NOW() AND NOT blocked) AS pending,
+			COUNT(*) FILTER (WHERE attempt_at IS NOT NULL AND NOT in_flight AND blocked) AS blocked,
+			COUNT(*) FILTER (WHERE attempt_at > NOW() AND NOT in_flight AND NOT blocked) AS delayed,
+			COUNT(*) FILTER (
+				WHERE attempt_at IS NULL OR (attempt_at > NOW() AND in_flight)
+			) AS in_flight,
+			(EXTRACT(EPOCH FROM NOW() - MIN(created_at)) * 1000)::BIGINT AS oldest_age_ms
+		FROM (
+			SELECT
+				mq_msgs.channel_name,
+				mq_msgs.attempt_at,
+				mq_msgs.created_at,
+				COALESCE(outcomes.in_flight, FALSE) AS in_flight,
+				mq_uuid_exists(mq_msgs.after_message_id) AS blocked
+			FROM mq_msgs
+			LEFT JOIN requeuest_outcomes AS outcomes ON outcomes.id = mq_msgs.id
+			WHERE mq_msgs.id != uuid_nil()
+		) AS queued
+		GROUP BY channel_name
+		ORDER BY channel_name ASC",
+	)
+	.fetch_all(pool)
+	.await?;
+
+	let count = |row: &PgRow, column: &str| -> Result<u64, sqlx::Error> {
+		let count: i64 = row.try_get(column)?;
+		Ok(u64::try_from(count).unwrap_or_default())
+	};
+	rows.iter()
+		.map(|row| {
+			let oldest_age: Option<i64> = row.try_get("oldest_age_ms")?;
+			Ok(QueueStats {
+				channel: row.try_get("channel_name")?,
+				pending: count(row, "pending")?,
+				blocked: count(row, "blocked")?,
+				delayed: count(row, "delayed")?,
+				in_flight: count(row, "in_flight")?,
+				oldest_age: Duration::from_millis(
+					oldest_age.and_then(|age| u64::try_from(age).ok()).unwrap_or_default(),
+				),
+			})
+		})
+		.collect()
+}
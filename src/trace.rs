@@ -0,0 +1,226 @@
+//! Instrumentation of spawning and sending requests with [`tracing`], enabled
+//! by the `tracing` feature. Spawning a request opens a span, and so does each
+//! attempt to send it, which is usually made by another task or process, so
+//! the spans can't be nested. They are linked by the `job_id` field instead.
+//! The [trace context](crate::trace_context) a request is spawned in is stored
+//! with the job, and recorded in the `trace_id` and `parent_span_id` fields of
+//! all of its spans. These spans have no IDs of their own in that trace, so
+//! the trace context is sent to servers unchanged.
+
+use std::{collections::HashSet, fmt, time::Duration};
+
+use reqwest::header::{
+	HeaderMap, HeaderName, AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION, SET_COOKIE,
+};
+use sqlxmq::CurrentJob;
+use tracing::{
+	field::{DisplayValue, Empty},
+	Span,
+};
+use uuid::Uuid;
+
+use crate::{
+	error::SpawnError,
+	request::Request,
+	status::{Attempt, Outcome},
+	trace_context::TraceContext,
+};
+
+/// The value redacted header values are replaced with.
+const REDACTED: &str = "[redacted]";
+
+/// Returns the headers whose values are redacted from traces by default.
+pub(crate) fn default_redacted_headers() -> HashSet<HeaderName> {
+	[AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE, SET_COOKIE, HeaderName::from_static("x-api-key")]
+		.into_iter()
+		.collect()
+}
+
+/// Formats headers with the values of sensitive ones redacted.
+struct Redacted<'a> {
+	/// The headers to format.
+	headers: &'a HeaderMap,
+	/// The headers to redact, in addition to ones marked as sensitive.
+	redacted: &'a HashSet<HeaderName>,
+}
+
+impl fmt::Debug for Redacted<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let entries = self.headers.iter().map(|(name, value)| {
+			let value = if value.is_sensitive() || self.redacted.contains(name) {
+				REDACTED
+			} else {
+				value.to_str().unwrap_or("[binary]")
+			};
+			(name.as_str(), value)
+		});
+		f.debug_map().entries(entries).finish()
+	}
+}
+
+/// Opens the span of spawning the given request as the job with the given
+/// UUID, in the given trace context.
+pub(crate) fn spawn_span(
+	uuid: Uuid,
+	channel: &str,
+	request: &Request,
+	context: Option<&TraceContext>,
+	redacted: &HashSet<HeaderName>,
+) -> Span {
+	tracing::info_span!(
+		"requeuest.spawn",
+		job_id = %uuid,
+		channel,
+		method = %request.method,
+		host = request.url.host_str().unwrap_or_default(),
+		headers = ?Redacted { headers: &request.headers, redacted },
+		trace_id = context.map(trace_id),
+		parent_span_id = context.map(parent_span_id),
+		deduplicated = Empty,
+		error = Empty,
+	)
+}
+
+/// Records the result of spawning a job in its span.
+pub(crate) fn spawned(span: &Span, uuid: Uuid, result: &Result<Uuid, SpawnError>) {
+	match result {
+		Ok(id) if *id == uuid => {
+			span.in_scope(|| tracing::debug!("request spawned"));
+		}
+		Ok(id) => {
+			span.record("deduplicated", true);
+			span.in_scope(|| tracing::debug!(original = %id, "request already spawned"));
+		}
+		Err(error) => {
+			span.record("error", tracing::field::display(error));
+			span.in_scope(|| tracing::warn!("failed to spawn request"));
+		}
+	}
+}
+
+/// Opens the span of spawning a batch of the given number of requests, in the
+/// given trace context.
+pub(crate) fn batch_span(channel: &str, requests: usize, context: Option<&TraceContext>) -> Span {
+	tracing::info_span!(
+		"requeuest.spawn_batch",
+		channel,
+		requests,
+		trace_id = context.map(trace_id),
+		parent_span_id = context.map(parent_span_id),
+		error = Empty,
+	)
+}
+
+/// Records the result of spawning a batch of jobs in its span.
+pub(crate) fn batch_spawned(span: &Span, result: &Result<Vec<Uuid>, SpawnError>) {
+	match result {
+		Ok(_) => span.in_scope(|| tracing::debug!("requests spawned")),
+		Err(error) => {
+			span.record("error", tracing::field::display(error));
+			span.in_scope(|| tracing::warn!("failed to spawn requests"));
+		}
+	}
+}
+
+/// Opens the span of an attempt to send the request of the given job, in the
+/// trace context the job was spawned in, if any.
+pub(crate) fn attempt_span(
+	job: &CurrentJob,
+	request: &Request,
+	attempt: &Attempt,
+	context: Option<&TraceContext>,
+) -> Span {
+	tracing::info_span!(
+		"requeuest.attempt",
+		job_id = %job.id(),
+		channel = attempt.channel.as_str(),
+		method = %request.method,
+		host = request.url.host_str().unwrap_or_default(),
+		attempt = attempt.number,
+		trace_id = context.map(trace_id),
+		parent_span_id = context.map(parent_span_id),
+		status = Empty,
+		latency_ms = Empty,
+		error = Empty,
+	)
+}
+
+/// Formats the trace ID of the given context as a span field.
+fn trace_id(context: &TraceContext) -> DisplayValue<String> {
+	tracing::field::display(format!("{:032x}", context.trace_id))
+}
+
+/// Formats the span ID of the given context as a span field.
+fn parent_span_id(context: &TraceContext) -> DisplayValue<String> {
+	tracing::field::display(format!("{:016x}", context.span_id))
+}
+
+/// Records the result of sending a request in the span of the attempt, where
+/// `accepted` is whether the response has an accepted status code.
+pub(crate) fn sent(span: &Span, outcome: &Outcome, accepted: bool, latency: Duration) {
+	span.record("latency_ms", u64::try_from(latency.as_millis()).unwrap_or(u64::MAX));
+	match outcome {
+		Outcome::Response(status) => span.record("status", status.as_u16()),
+		Outcome::Error(error) => span.record("error", error.as_str()),
+	};
+	if accepted {
+		span.in_scope(|| tracing::info!("request delivered"));
+	}
+}
+
+/// Records that an attempt failed with the given outcome, where `buried` is
+/// whether the request was given up on and moved to the dead letter table.
+pub(crate) fn failed(span: &Span, outcome: &Outcome, buried: bool) {
+	let _entered = span.enter();
+	let (status, error) = match outcome {
+		Outcome::Response(status) => (Some(status.as_u16()), None),
+		Outcome::Error(error) => (None, Some(error.as_str())),
+	};
+	if buried {
+		tracing::error!(status, error, "giving up on request");
+	} else {
+		tracing::warn!(status, error, "attempt failed");
+	}
+}
+
+/// Records that the given job was completed without an attempt, because its
+/// request expired.
+pub(crate) fn expired(job: &CurrentJob) {
+	tracing::info!(job_id = %job.id(), "request expired");
+}
+
+/// Records that the given job was postponed by the given amount of time,
+/// because of a rate limit or an open circuit.
+pub(crate) fn postponed(job: &CurrentJob, wait: Duration) {
+	let wait_ms = u64::try_from(wait.as_millis()).unwrap_or(u64::MAX);
+	tracing::debug!(job_id = %job.id(), wait_ms, "request postponed");
+}
+
+#[cfg(test)]
+mod tests {
+	#![allow(clippy::unwrap_used)]
+	use std::collections::HashSet;
+
+	use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+
+	use super::{default_redacted_headers, Redacted};
+
+	#[test]
+	fn redaction() {
+		let mut headers = HeaderMap::new();
+		headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer secret"));
+		headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+		let mut token = HeaderValue::from_static("secret");
+		token.set_sensitive(true);
+		headers.insert("x-token", token);
+
+		let redacted = default_redacted_headers();
+		let formatted = format!("{:?}", Redacted { headers: &headers, redacted: &redacted });
+		assert!(!formatted.contains("secret"), "Sensitive header wasn't redacted");
+		assert!(formatted.contains("application/json"), "Header was redacted");
+
+		let formatted = format!("{:?}", Redacted { headers: &headers, redacted: &HashSet::new() });
+		assert!(formatted.contains("Bearer secret"), "Header was redacted");
+		assert!(!formatted.contains("\"secret\""), "Sensitive header wasn't redacted");
+	}
+}
@@ -0,0 +1,272 @@
+//! Propagation of the [W3C trace context](https://www.w3.org/TR/trace-context/)
+//! across the queue. The application can run code in a trace context with
+//! [`TraceContext::scope`], e.g. one extracted from the headers of an incoming
+//! request, or from its current OpenTelemetry span. Requests spawned in it
+//! store the context with their job when the client is built with
+//! [`ClientBuilder::propagate_trace_context`](crate::client::ClientBuilder::propagate_trace_context),
+//! and the job runner sends it in `traceparent` and `tracestate` headers, so
+//! the trace continues at the server receiving the request.
+//!
+//! With the `tracing` feature, requests spawned outside of a scope continue the
+//! OpenTelemetry context of the current [`tracing`](https://docs.rs/tracing)
+//! span instead, if the application reports its spans with
+//! [`tracing-opentelemetry`](https://docs.rs/tracing-opentelemetry). Without
+//! it, the context has to be passed to [`TraceContext::scope`] explicitly. It's
+//! sent unchanged, so the span whose IDs it holds is the parent of the server's
+//! span, as the queue itself doesn't report any spans to the trace.
+
+use std::future::Future;
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+use sqlxmq::CurrentJob;
+
+/// The header the position of the sending span within its trace is sent in.
+const TRACEPARENT: HeaderName = HeaderName::from_static("traceparent");
+/// The header vendor specific trace data is sent in.
+const TRACESTATE: HeaderName = HeaderName::from_static("tracestate");
+
+tokio::task_local! {
+	/// The trace context the current task runs in.
+	static CURRENT: TraceContext;
+}
+
+/// The position of a span within a trace, in the format of the W3C trace
+/// context.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+	/// The ID of the whole trace.
+	pub trace_id: u128,
+	/// The ID of the span within the trace.
+	pub span_id: u64,
+	/// Whether the trace is being recorded.
+	pub sampled: bool,
+	/// The vendor specific data of the trace, in the format of a `tracestate`
+	/// header.
+	pub trace_state: Option<String>,
+}
+
+impl TraceContext {
+	/// Constructs the context of the root span of a new, sampled trace.
+	#[must_use]
+	pub fn root() -> Self {
+		Self {
+			trace_id: fastrand::u128(1..),
+			span_id: fastrand::u64(1..),
+			sampled: true,
+			trace_state: None,
+		}
+	}
+
+	/// Constructs the context of a new span within the same trace.
+	#[must_use]
+	pub fn child(&self) -> Self {
+		Self { span_id: fastrand::u64(1..), ..self.clone() }
+	}
+
+	/// Parses the values of a `traceparent` and a `tracestate` header. Returns
+	/// `None` if the traceparent is malformed, and ignores the tracestate if it
+	/// is.
+	pub fn parse(traceparent: &str, tracestate: Option<&str>) -> Option<Self> {
+		let mut parts = traceparent.split('-');
+		let (Some(version), Some(trace_id), Some(span_id), Some(flags)) =
+			(parts.next(), parts.next(), parts.next(), parts.next())
+		else {
+			return None;
+		};
+		if version.len() != 2 || version == "ff" || (version == "00" && parts.next().is_some()) {
+			return None;
+		}
+		if trace_id.len() != 32 || span_id.len() != 16 || flags.len() != 2 {
+			return None;
+		}
+		let context = Self {
+			trace_id: u128::from_str_radix(trace_id, 16).ok()?,
+			span_id: u64::from_str_radix(span_id, 16).ok()?,
+			sampled: u8::from_str_radix(flags, 16).ok()? & 1 == 1,
+			trace_state: tracestate
+				.filter(|state| HeaderValue::from_str(state).is_ok())
+				.map(str::to_owned),
+		};
+		(context.trace_id != 0 && context.span_id != 0).then_some(context)
+	}
+
+	/// Formats the context as the value of a `traceparent` header.
+	#[must_use]
+	pub fn traceparent(&self) -> String {
+		format!("00-{:032x}-{:016x}-{:02x}", self.trace_id, self.span_id, u8::from(self.sampled))
+	}
+
+	/// Returns the trace context the current task runs in, if it was given one
+	/// with [`TraceContext::scope`]. Otherwise, with the `tracing` feature,
+	/// it's the OpenTelemetry context of the current `tracing` span, if it has
+	/// a valid one.
+	pub fn current() -> Option<Self> {
+		let scoped = CURRENT.try_with(Clone::clone).ok();
+		#[cfg(feature = "tracing")]
+		let scoped = scoped.or_else(Self::of_current_span);
+		scoped
+	}
+
+	/// Reads the OpenTelemetry context of the current `tracing` span, which is
+	/// only valid if the span is reported by a `tracing-opentelemetry` layer.
+	#[cfg(feature = "tracing")]
+	fn of_current_span() -> Option<Self> {
+		use opentelemetry::trace::TraceContextExt;
+		use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+		let context = tracing::Span::current().context();
+		let span = context.span();
+		let span_context = span.span_context();
+		let trace_state = span_context.trace_state().header();
+		span_context.is_valid().then(|| Self {
+			trace_id: u128::from_be_bytes(span_context.trace_id().to_bytes()),
+			span_id: u64::from_be_bytes(span_context.span_id().to_bytes()),
+			sampled: span_context.is_sampled(),
+			trace_state: Some(trace_state)
+				.filter(|state| !state.is_empty() && HeaderValue::from_str(state).is_ok()),
+		})
+	}
+
+	/// Runs the given future in this trace context, which requests spawned by
+	/// it continue.
+	///
+	/// # Example
+	/// ```no_run
+	/// # use requeuest::{trace_context::TraceContext, Client, Request};
+	/// # async fn example(client: Client, request: Request, traceparent: &str) -> Result<(), Box<dyn std::error::Error>> {
+	/// let context = TraceContext::parse(traceparent, None).ok_or("malformed traceparent")?;
+	/// context.scope(client.spawn("my_service", &request)).await?;
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub async fn scope<F: Future>(self, future: F) -> F::Output {
+		CURRENT.scope(self, future).await
+	}
+
+	/// Formats the context as the JSON payload it's stored with jobs as, where
+	/// `propagate` is whether it's sent along with the request.
+	pub(crate) fn to_json(&self, propagate: bool) -> String {
+		serde_json::json!({
+			"traceparent": self.traceparent(),
+			"tracestate": self.trace_state,
+			"propagate": propagate,
+		})
+		.to_string()
+	}
+
+	/// Reads the context stored with the given job, if there is one, along
+	/// with whether it's sent along with the request.
+	pub(crate) fn of_job(job: &CurrentJob) -> Option<(Self, bool)> {
+		/// The JSON payload trace contexts are stored with jobs as.
+		#[derive(Deserialize)]
+		struct Stored {
+			/// The position of the spawning span within its trace.
+			traceparent: String,
+			/// The vendor specific data of the trace.
+			#[serde(default)]
+			tracestate: Option<String>,
+			/// Whether the context is sent along with the request.
+			#[serde(default)]
+			propagate: bool,
+		}
+		let stored: Stored = job.json().ok().flatten()?;
+		let context = Self::parse(&stored.traceparent, stored.tracestate.as_deref())?;
+		Some((context, stored.propagate))
+	}
+
+	/// Inserts the `traceparent` and `tracestate` headers of the context into
+	/// the given headers, replacing any already there.
+	pub(crate) fn inject(&self, headers: &mut HeaderMap) {
+		if let Ok(traceparent) = HeaderValue::from_str(&self.traceparent()) {
+			headers.insert(TRACEPARENT, traceparent);
+		}
+		match self.trace_state.as_deref().map(HeaderValue::from_str) {
+			Some(Ok(tracestate)) => headers.insert(TRACESTATE, tracestate),
+			_ => headers.remove(TRACESTATE),
+		};
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	#![allow(clippy::unwrap_used)]
+	use reqwest::header::HeaderMap;
+
+	use super::TraceContext;
+
+	#[test]
+	fn traceparent() {
+		let context =
+			TraceContext { trace_id: 0xabc, span_id: 0x42, sampled: true, trace_state: None };
+		let traceparent = context.traceparent();
+		assert_eq!(traceparent, "00-00000000000000000000000000000abc-0000000000000042-01");
+		assert_eq!(TraceContext::parse(&traceparent, None), Some(context.clone()));
+
+		let child = context.child();
+		assert_eq!(child.trace_id, context.trace_id);
+		assert_ne!(child.span_id, 0);
+
+		let unsampled = TraceContext::parse(&traceparent.replace("-01", "-00"), None).unwrap();
+		assert!(!unsampled.sampled);
+		assert!(unsampled.traceparent().ends_with("-00"));
+
+		assert_eq!(TraceContext::parse("00-abc-42-01", None), None);
+		assert_eq!(TraceContext::parse(&format!("ff{}", &traceparent[2..]), None), None);
+		assert_eq!(TraceContext::parse(&format!("{traceparent}-00"), None), None);
+		assert_eq!(
+			TraceContext::parse("00-00000000000000000000000000000000-0000000000000042-01", None),
+			None
+		);
+		assert_eq!(TraceContext::parse("garbage", None), None);
+	}
+
+	#[test]
+	fn injection() {
+		let context = TraceContext::parse(
+			"00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+			Some("congo=t61rcWkgMzE"),
+		)
+		.unwrap();
+		let mut headers = HeaderMap::new();
+		headers.insert("tracestate", "stale".parse().unwrap());
+		context.inject(&mut headers);
+		assert_eq!(
+			headers["traceparent"],
+			"00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+		);
+		assert_eq!(headers["tracestate"], "congo=t61rcWkgMzE");
+
+		let context = TraceContext { trace_state: None, ..context };
+		context.inject(&mut headers);
+		assert!(!headers.contains_key("tracestate"), "Stale tracestate was sent");
+
+		let malformed = TraceContext::parse(&context.traceparent(), Some("bad\nstate")).unwrap();
+		assert_eq!(malformed.trace_state, None);
+	}
+
+	#[cfg(feature = "tracing")]
+	#[test]
+	fn current_span() {
+		use opentelemetry::trace::TracerProvider;
+		use tracing_subscriber::layer::SubscriberExt;
+
+		let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder().build();
+		let subscriber = tracing_subscriber::registry()
+			.with(tracing_opentelemetry::layer().with_tracer(provider.tracer("requeuest")));
+		let _guard = tracing::subscriber::set_default(subscriber);
+
+		assert_eq!(TraceContext::current(), None);
+
+		let span = tracing::info_span!("spawning");
+		let _entered = span.enter();
+		let current = TraceContext::current().unwrap();
+		assert_ne!(current.trace_id, 0);
+		assert_ne!(current.span_id, 0);
+		assert!(current.sampled);
+
+		let scoped = TraceContext::root();
+		let current = super::CURRENT.sync_scope(scoped.clone(), TraceContext::current);
+		assert_eq!(current, Some(scoped), "Scope didn't take precedence over the span");
+	}
+}
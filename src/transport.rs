@@ -0,0 +1,232 @@
+//! The HTTP clients the job runner sends requests with. By default, requests
+//! are sent with a plain [`reqwest::Client`]. To send them through a proxy,
+//! with custom root certificates or client certificates, or with other
+//! connection settings, configure an [`HttpClient`] with
+//! [`ClientBuilder::http_client`](crate::client::ClientBuilder::http_client),
+//! or for the requests spawned on a channel with
+//! [`ClientBuilder::channel_http_client`](crate::client::ClientBuilder::channel_http_client).
+
+use std::{
+	collections::{HashMap, VecDeque},
+	fmt,
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use crate::request::Request;
+
+/// The granularity connect timeouts of requests are rounded up to, so similar
+/// timeouts share a client.
+const CONNECT_TIMEOUT_GRANULARITY: Duration = Duration::from_millis(100);
+/// How many clients for the connect timeouts of requests are kept, each with
+/// its own connection pool. The least recently used one is dropped when
+/// another one is needed.
+const CONNECT_TIMEOUT_CLIENTS: usize = 16;
+
+/// How the job runner gets the HTTP clients it sends requests with.
+///
+/// # Example
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use requeuest::{reqwest, transport::HttpClient};
+///
+/// let proxy = reqwest::Proxy::all("http://egress.internal:3128")?;
+/// let configure = move || reqwest::Client::builder().proxy(proxy.clone());
+/// let http_client = HttpClient::config(configure);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub enum HttpClient {
+	/// Requests are sent with the given client. The connect timeouts of the
+	/// client builder and of requests don't apply, as reqwest only supports
+	/// setting them when building a client.
+	Client(reqwest::Client),
+	/// Clients are built from the builders the given function returns, with
+	/// the connect timeout applied.
+	Config(Arc<dyn Fn() -> reqwest::ClientBuilder + Send + Sync>),
+}
+
+impl HttpClient {
+	/// Builds clients from the builders the given function returns.
+	pub fn config(configure: impl Fn() -> reqwest::ClientBuilder + Send + Sync + 'static) -> Self {
+		Self::Config(Arc::new(configure))
+	}
+
+	/// Builds a client with the given connect timeout, if it's configurable.
+	fn build(&self, connect_timeout: Option<Duration>) -> Result<reqwest::Client, reqwest::Error> {
+		match self {
+			HttpClient::Client(client) => Ok(client.clone()),
+			HttpClient::Config(configure) => {
+				let mut builder = configure();
+				if let Some(connect_timeout) = connect_timeout {
+					builder = builder.connect_timeout(connect_timeout);
+				}
+				builder.build()
+			}
+		}
+	}
+}
+
+impl Default for HttpClient {
+	fn default() -> Self {
+		Self::config(reqwest::Client::builder)
+	}
+}
+
+impl From<reqwest::Client> for HttpClient {
+	fn from(client: reqwest::Client) -> Self {
+		Self::Client(client)
+	}
+}
+
+impl fmt::Debug for HttpClient {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			HttpClient::Client(client) => f.debug_tuple("Client").field(client).finish(),
+			HttpClient::Config(_) => f.debug_tuple("Config").finish_non_exhaustive(),
+		}
+	}
+}
+
+/// The HTTP clients used by the job runner, along with the default timeouts
+/// for requests which don't set their own.
+#[derive(Debug, Clone)]
+pub(crate) struct Transport(Arc<Inner>);
+
+/// The shared state of a [`Transport`].
+#[derive(Debug)]
+struct Inner {
+	/// The clients requests are sent with by default.
+	clients: Clients,
+	/// The clients requests spawned on a channel are sent with, by channel.
+	channel_clients: HashMap<String, Clients>,
+	/// The default timeout of each attempt.
+	timeout: Option<Duration>,
+	/// The default connect timeout of each attempt.
+	connect_timeout: Option<Duration>,
+}
+
+/// The clients built from the same [`HttpClient`].
+#[derive(Debug)]
+struct Clients {
+	/// How the clients are built.
+	http_client: HttpClient,
+	/// The client with the default connect timeout.
+	client: reqwest::Client,
+	/// Clients for requests which set their own connect timeout, since
+	/// reqwest only supports setting it for a whole client, the most recently
+	/// used first.
+	connect_timeout_clients: Mutex<VecDeque<(Duration, reqwest::Client)>>,
+}
+
+impl Clients {
+	/// Builds the client with the given default connect timeout.
+	fn new(
+		http_client: HttpClient,
+		connect_timeout: Option<Duration>,
+	) -> Result<Self, reqwest::Error> {
+		Ok(Self {
+			client: http_client.build(connect_timeout)?,
+			http_client,
+			connect_timeout_clients: Mutex::new(VecDeque::new()),
+		})
+	}
+}
+
+impl Transport {
+	/// Constructs a transport with the given clients and default timeouts.
+	pub fn new(
+		http_client: HttpClient,
+		channel_http_clients: HashMap<String, HttpClient>,
+		timeout: Option<Duration>,
+		connect_timeout: Option<Duration>,
+	) -> Result<Self, reqwest::Error> {
+		let channel_clients = channel_http_clients
+			.into_iter()
+			.map(|(channel, http_client)| {
+				Ok((channel, Clients::new(http_client, connect_timeout)?))
+			})
+			.collect::<Result<_, reqwest::Error>>()?;
+		Ok(Self(Arc::new(Inner {
+			clients: Clients::new(http_client, connect_timeout)?,
+			channel_clients,
+			timeout,
+			connect_timeout,
+		})))
+	}
+
+	/// Returns the client to send the given request spawned on the given
+	/// channel with. Connect timeouts of requests are rounded up to the next
+	/// tenth of a second.
+	pub fn client(
+		&self,
+		channel: &str,
+		request: &Request,
+	) -> Result<reqwest::Client, reqwest::Error> {
+		let clients = self.0.channel_clients.get(channel).unwrap_or(&self.0.clients);
+		let connect_timeout = match (request.connect_timeout, &clients.http_client) {
+			(Some(timeout), HttpClient::Config(_)) if Some(timeout) != self.0.connect_timeout => {
+				round_up(timeout)
+			}
+			_ => return Ok(clients.client.clone()),
+		};
+
+		#[allow(clippy::unwrap_used)] // We don't handle poisoning
+		let mut connect_timeout_clients = clients.connect_timeout_clients.lock().unwrap();
+		let position =
+			connect_timeout_clients.iter().position(|(timeout, _)| *timeout == connect_timeout);
+		let entry = match position.and_then(|position| connect_timeout_clients.remove(position)) {
+			Some(entry) => entry,
+			None => {
+				connect_timeout_clients.truncate(CONNECT_TIMEOUT_CLIENTS - 1);
+				(connect_timeout, clients.http_client.build(Some(connect_timeout))?)
+			}
+		};
+		let client = entry.1.clone();
+		connect_timeout_clients.push_front(entry);
+		Ok(client)
+	}
+
+	/// Returns the timeout of a single attempt to send the given request.
+	pub fn timeout(&self, request: &Request) -> Option<Duration> {
+		request.timeout.or(self.0.timeout)
+	}
+}
+
+/// Rounds the given connect timeout up to the granularity of the clients built
+/// for them.
+fn round_up(timeout: Duration) -> Duration {
+	let granularity = CONNECT_TIMEOUT_GRANULARITY.as_nanos();
+	let buckets = timeout.as_nanos().div_ceil(granularity);
+	u32::try_from(buckets).map_or(timeout, |buckets| CONNECT_TIMEOUT_GRANULARITY * buckets)
+}
+
+#[cfg(test)]
+mod tests {
+	#![allow(clippy::unwrap_used)]
+	use std::{collections::HashMap, time::Duration};
+
+	use super::{round_up, HttpClient, Transport, CONNECT_TIMEOUT_CLIENTS};
+	use crate::request::Request;
+
+	#[test]
+	fn connect_timeout_clients() {
+		assert_eq!(round_up(Duration::from_millis(1)), Duration::from_millis(100));
+		assert_eq!(round_up(Duration::from_millis(200)), Duration::from_millis(200));
+		assert_eq!(round_up(Duration::from_millis(201)), Duration::from_millis(300));
+		assert_eq!(round_up(Duration::MAX), Duration::MAX);
+
+		let transport = Transport::new(HttpClient::default(), HashMap::new(), None, None).unwrap();
+		for millis in (50..3000).step_by(100) {
+			let request = Request::get("https://example.com/")
+				.unwrap()
+				.connect_timeout(Duration::from_millis(millis))
+				.build();
+			transport.client("channel", &request).unwrap();
+		}
+		let clients = transport.0.clients.connect_timeout_clients.lock().unwrap();
+		assert_eq!(clients.len(), CONNECT_TIMEOUT_CLIENTS, "Clients weren't bounded");
+		assert_eq!(clients[0].0, Duration::from_secs(3), "Latest client wasn't kept");
+	}
+}